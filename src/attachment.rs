@@ -0,0 +1,267 @@
+use std::sync::Arc;
+
+use axum::{
+	body::Body,
+	extract::{Multipart, Path, Query, State},
+	http::header,
+	response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{headers, TypedHeader};
+use bincode::{Decode, Encode};
+use percent_encoding::utf8_percent_encode;
+use rocksdb::TransactionDB;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+	auth,
+	auth::{namespace::Namespace, user::User},
+	errors::WkError,
+	exists, not_found, ok, page,
+	page::Page,
+	render, thumbnail, Context, BLOB_CF,
+};
+
+/// Largest upload accepted, all files of a request together.
+pub const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// Served in place, anything else is downloaded. Notably not HTML or SVG,
+/// those could run scripts as the wiki.
+const INLINE_TYPES: &[&str] = &[
+	"image/png",
+	"image/jpeg",
+	"image/gif",
+	"image/webp",
+	"application/pdf",
+	"text/plain",
+];
+
+/// Markdown links to `file:name` point at the page's attachment `name`.
+const LINK_PREFIX: &str = "](file:";
+
+/// A file attached to a page. The content lives in `BLOB_CF` under its hash,
+/// so the same file attached twice is stored once.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Attachment {
+	pub name: String,
+	/// SHA-256, hex.
+	pub hash: String,
+	pub size: u64,
+	pub content_type: String,
+}
+
+impl Attachment {
+	/// Human readable size, e.g. "1.5 MiB".
+	pub fn display_size(&self) -> String {
+		if self.size < 1024 {
+			return format!("{} B", self.size);
+		}
+		let mut size = self.size as f64 / 1024.0;
+		for unit in ["KiB", "MiB"] {
+			if size < 1024.0 {
+				return format!("{size:.1} {unit}");
+			}
+			size /= 1024.0;
+		}
+		format!("{size:.1} GiB")
+	}
+}
+
+#[derive(Deserialize)]
+pub struct DownloadParams {
+	/// Width to scale images down to, e.g. `file:diagram.png?w=320`.
+	w: Option<u32>,
+}
+
+/// An attachment as listed on a page.
+pub struct FileLink {
+	pub name: String,
+	pub url: String,
+	pub size: String,
+}
+
+impl FileLink {
+	pub fn new(ns: &str, slug: &str, attachment: &Attachment) -> Self {
+		Self {
+			name: attachment.name.clone(),
+			url: url(ns, slug, &attachment.name),
+			size: attachment.display_size(),
+		}
+	}
+}
+
+/// Stores some content, returning its hash.
+async fn put_blob(db: &TransactionDB, bytes: &[u8]) -> Result<String, WkError> {
+	let hash = format!("{:x}", Sha256::digest(bytes));
+	let cf = db.cf_handle(BLOB_CF).unwrap();
+	if db.get_cf(&cf, &hash)?.is_none() {
+		db.put_cf(&cf, &hash, bytes)?;
+	}
+	Ok(hash)
+}
+
+pub async fn get_blob(
+	db: &TransactionDB,
+	hash: &str,
+) -> Result<Option<Vec<u8>>, WkError> {
+	let cf = db.cf_handle(BLOB_CF).unwrap();
+	Ok(db.get_cf(&cf, hash)?)
+}
+
+/// File names keep to one URL segment and are linked without escaping, e.g.
+/// "C:\shots\my diagram.png" => "my_diagram.png".
+fn clean_name(name: &str) -> String {
+	let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+	base.chars()
+		.filter(|c| !c.is_control() && !"#?%<>\"".contains(*c))
+		.collect::<String>()
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join("_")
+		.trim_start_matches('.')
+		.to_string()
+}
+
+/// Absolute, percent-encoded URL of a page's attachment.
+pub fn url(ns: &str, slug: &str, name: &str) -> String {
+	format!(
+		"{}/files/{}",
+		page::url(ns, slug),
+		utf8_percent_encode(name, page::PATH_SEGMENT)
+	)
+}
+
+/// Points `![](file:name)` and `[](file:name)` links in a page's body at its
+/// attachments. Links in code are left alone.
+pub fn link_files(ns: &str, slug: &str, body: &str) -> String {
+	let files = format!("]({}/files/", page::url(ns, slug));
+	let code = render::code_ranges(body);
+	let mut linked = String::with_capacity(body.len());
+	let mut last = 0;
+	for (start, _) in body.match_indices(LINK_PREFIX) {
+		if code.iter().any(|range| range.contains(&start)) {
+			continue;
+		}
+		linked.push_str(&body[last..start]);
+		linked.push_str(&files);
+		last = start + LINK_PREFIX.len();
+	}
+	linked.push_str(&body[last..]);
+	linked
+}
+
+/// Attaches the uploaded files to a page, replacing attachments of the same
+/// name.
+pub async fn upload(
+	Path((ns, slug)): Path<(String, String)>,
+	State(ctx): State<Arc<Context>>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	mut multipart: Multipart,
+) -> Response {
+	let Context { db, search, .. } = ctx.as_ref();
+
+	let user = ok!(User::authenticated(db, cookies).await);
+	let ns = exists!(ok!(Namespace::get(db, &ns).await));
+	if !ns.user_has_access(&user, auth::READ) {
+		return not_found().await.into_response();
+	}
+
+	let mut page = exists!(Page::get(db, &ns.name, &slug).await);
+	if !page.user_has_access(&user, &ns.name, auth::WRITE) {
+		return Redirect::to(&format!(
+			"{}/edit?error=EPERM",
+			page::url(&ns.name, &slug)
+		))
+		.into_response();
+	}
+
+	// Body size is capped by the route, see `main`.
+	while let Some(field) = ok!(multipart
+		.next_field()
+		.await
+		.map_err(|_| WkError::InvalidArgument))
+	{
+		let name = clean_name(field.file_name().unwrap_or_default());
+		// Sent back as a header on download, so it must be one.
+		let content_type = field
+			.content_type()
+			.filter(|t| {
+				t.contains('/') && t.chars().all(|c| c.is_ascii_graphic())
+			})
+			.unwrap_or("application/octet-stream")
+			.to_ascii_lowercase();
+		let bytes =
+			ok!(field.bytes().await.map_err(|_| WkError::InvalidArgument));
+		// Browsers send an empty, nameless file when none was picked.
+		if name.is_empty() && bytes.is_empty() {
+			continue;
+		}
+		if name.is_empty() {
+			return WkError::InvalidArgument.into_response();
+		}
+
+		let hash = ok!(put_blob(db, &bytes).await);
+		page.attach(Attachment {
+			name,
+			hash,
+			size: bytes.len() as u64,
+			content_type,
+		});
+	}
+
+	ok!(Page::put(db, &ns.name, &mut page).await);
+	search.update_index(&ns.name, page).await;
+
+	Redirect::to(&format!("{}/edit", page::url(&ns.name, &slug)))
+		.into_response()
+}
+
+pub async fn download(
+	Path((ns, slug, name)): Path<(String, String, String)>,
+	Query(params): Query<DownloadParams>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	State(ctx): State<Arc<Context>>,
+) -> Response {
+	let Context { db, .. } = ctx.as_ref();
+
+	let user = ok!(User::authenticated(db, cookies).await);
+	let ns = exists!(ok!(Namespace::get(db, &ns).await));
+	if !ns.user_has_access(&user, auth::READ) {
+		return not_found().await.into_response();
+	}
+
+	let page = exists!(Page::get(db, &ns.name, &slug)
+		.await
+		.filter(|page| page.user_has_access(&user, &ns.name, auth::READ)));
+	let attachment = exists!(page.attachment(&name));
+
+	if let Some(width) = params.w {
+		if let Some((bytes, content_type)) =
+			ok!(thumbnail::get(db, attachment, width).await)
+		{
+			let etag = format!("{}-w{width}", attachment.hash);
+			return serve(content_type, &etag, bytes);
+		}
+	}
+
+	let bytes = exists!(ok!(get_blob(db, &attachment.hash).await));
+	serve(&attachment.content_type, &attachment.hash, bytes)
+}
+
+fn serve(content_type: &str, etag: &str, bytes: Vec<u8>) -> Response {
+	let disposition = if INLINE_TYPES.contains(&content_type) {
+		"inline"
+	} else {
+		"attachment"
+	};
+	(
+		[
+			(header::CONTENT_TYPE, content_type.to_string()),
+			(header::CONTENT_DISPOSITION, disposition.to_string()),
+			(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+			(header::ETAG, format!("\"{etag}\"")),
+		],
+		Body::from(bytes),
+	)
+		.into_response()
+}
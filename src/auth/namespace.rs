@@ -8,6 +8,9 @@ use crate::{
 	auth::{has_access, user::User},
 	encoding::{DbDecode, DbEncode},
 	errors::WkError,
+	page::Page,
+	render::MarkdownOptions,
+	search::language,
 	NSPC_CF,
 };
 
@@ -23,6 +26,35 @@ pub struct Namespace {
 	pub name: String,
 	pub owner: String,
 	pub members: HashSet<String>,
+	pub markdown: MarkdownOptions,
+	/// Code of the language pages are searched in, see `language::LANGUAGES`.
+	/// `None` matches words only as written.
+	pub language: Option<String>,
+}
+
+/// `Namespace` as stored before markdown options and search languages, see
+/// `upgrade`.
+#[derive(Decode)]
+pub struct NamespaceV0 {
+	mode: u16,
+	umask: u16,
+	name: String,
+	owner: String,
+	members: HashSet<String>,
+}
+
+impl From<NamespaceV0> for Namespace {
+	fn from(old: NamespaceV0) -> Self {
+		Self {
+			mode: old.mode,
+			umask: old.umask,
+			name: old.name,
+			owner: old.owner,
+			members: old.members,
+			markdown: MarkdownOptions::default(),
+			language: Some(language::DEFAULT.to_string()),
+		}
+	}
 }
 
 impl Namespace {
@@ -37,6 +69,8 @@ impl Namespace {
 			owner: owner.to_string(),
 			umask: Self::DEFAULT_UMASK,
 			members: HashSet::new(),
+			markdown: MarkdownOptions::default(),
+			language: Some(language::DEFAULT.to_string()),
 		}
 	}
 
@@ -61,6 +95,18 @@ impl Namespace {
 		iter.flatten().map(|(_, v)| Namespace::dec(v)).collect()
 	}
 
+	/// Mode for a new page in this namespace. Requested modes may not grant
+	/// anything the namespace's umask withholds.
+	pub fn page_mode(&self, requested: Option<u16>) -> Result<u16, WkError> {
+		match requested {
+			None => Ok(Page::DEFAULT_MODE & !self.umask),
+			Some(mode) if mode > 0o777 || mode & self.umask != 0 => {
+				Err(WkError::InvalidArgument)
+			}
+			Some(mode) => Ok(mode),
+		}
+	}
+
 	pub fn user_has_access(&self, user: &Option<User>, access: u16) -> bool {
 		let owner_group = if let Some(user) = user {
 			if user.name == User::META {
@@ -79,13 +125,13 @@ impl Namespace {
 
 	pub async fn list_with_access(
 		db: &TransactionDB,
-		user: Option<User>,
+		user: &Option<User>,
 		access: u16,
 	) -> Vec<Namespace> {
 		Self::list(db)
 			.await
 			.into_iter()
-			.filter(|ns| ns.user_has_access(&user, access))
+			.filter(|ns| ns.user_has_access(user, access))
 			.collect()
 	}
 }
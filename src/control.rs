@@ -10,8 +10,10 @@ use serde::Deserialize;
 
 use crate::{
 	auth::{add_user_to_namespace, namespace::Namespace, user::User},
+	errors::WkError,
 	exists, ok,
 	page::Page,
+	search::language,
 	Context, CONTROL_HTML,
 };
 
@@ -35,6 +37,25 @@ pub enum ControlParams {
 		namespace: String,
 		mode: String,
 	},
+	SetNamespaceMarkdown {
+		namespace: String,
+		/// Extensions to turn on, see `MarkdownOptions::set_extensions`.
+		extensions: String,
+	},
+	SetNamespaceHtml {
+		namespace: String,
+		/// "keep" or "escape".
+		raw_html: String,
+	},
+	SetNamespaceLanguage {
+		namespace: String,
+		/// A code from `language::LANGUAGES`, or "none".
+		language: String,
+	},
+	Rebuild {
+		/// What to rebuild, only "search" for now.
+		rebuild: String,
+	},
 }
 
 pub async fn get(
@@ -100,6 +121,52 @@ pub async fn post(
 				dbg!(ns);
 				Redirect::to("/control?success=YES").into_response()
 			}
+			ControlParams::SetNamespaceMarkdown {
+				namespace,
+				extensions,
+			} => {
+				let mut ns =
+					exists!(ok!(Namespace::get(&state.db, &namespace).await));
+				ok!(ns.markdown.set_extensions(&extensions));
+				ok!(Namespace::put(&state.db, &ns).await);
+				Redirect::to("/control?success=YES").into_response()
+			}
+			ControlParams::SetNamespaceHtml {
+				namespace,
+				raw_html,
+			} => {
+				let mut ns =
+					exists!(ok!(Namespace::get(&state.db, &namespace).await));
+				ns.markdown.raw_html = match raw_html.as_str() {
+					"keep" => true,
+					"escape" => false,
+					_ => return WkError::InvalidArgument.into_response(),
+				};
+				ok!(Namespace::put(&state.db, &ns).await);
+				Redirect::to("/control?success=YES").into_response()
+			}
+			ControlParams::SetNamespaceLanguage {
+				namespace,
+				language,
+			} => {
+				let mut ns =
+					exists!(ok!(Namespace::get(&state.db, &namespace).await));
+				ns.language = match language.as_str() {
+					"none" => None,
+					code if language::is_supported(code) => Some(language),
+					_ => return WkError::InvalidArgument.into_response(),
+				};
+				ok!(Namespace::put(&state.db, &ns).await);
+				state.search.set_language(&ns.name, ns.language).await;
+				Redirect::to("/control?success=YES").into_response()
+			}
+			ControlParams::Rebuild { rebuild } => {
+				if rebuild != "search" {
+					return WkError::InvalidArgument.into_response();
+				}
+				state.search.rebuild().await;
+				Redirect::to("/control?success=YES").into_response()
+			}
 			ControlParams::SetPageMode {
 				namespace,
 				slug,
@@ -108,8 +175,9 @@ pub async fn post(
 				let mut page =
 					exists!(Page::get(&state.db, &namespace, &slug).await);
 				page.mode = u16::from_str_radix(&mode, 8).unwrap();
-				Page::put(&state.db, &namespace, &page).await;
-				dbg!(page);
+				ok!(Page::put(&state.db, &namespace, &mut page).await);
+				// Search results are filtered by page mode.
+				state.search.update_index(&namespace, page).await;
 
 				Redirect::to("/control?success=YES").into_response()
 			}
@@ -1,29 +1,78 @@
 use std::sync::Arc;
 
+use askama::Template;
 use axum::{
 	extract::State,
 	response::{Html, IntoResponse, Redirect},
 	Form,
 };
 use axum_extra::{headers, TypedHeader};
+use rocksdb::TransactionDB;
 use serde::Deserialize;
+use time::OffsetDateTime;
 
 use crate::{
 	auth,
 	auth::{add_user_to_namespace, namespace::Namespace, user::User},
-	ok,
+	errors::WkError,
+	front_matter,
+	history::db::HistoryRecord,
+	include, ok, page,
 	page::Page,
-	Context, CREATE_HTML,
+	Context,
 };
 
+#[derive(Template)]
+#[template(path = "create.html")]
+struct CreateTemplate {
+	templates: Vec<TemplateOption>,
+}
+
+struct TemplateOption {
+	namespace: String,
+	slug: String,
+	title: String,
+}
+
 #[derive(Deserialize)]
 pub struct CreatePayload {
 	title: String,
 	namespace: String,
+	#[serde(default)]
+	content: String,
+	/// Octal, e.g. "644". Empty for the namespace's default.
+	#[serde(default)]
+	mode: String,
+	/// "namespace/slug" of a template page, used when `content` is empty.
+	#[serde(default)]
+	template: String,
 }
 
-pub async fn get() -> impl IntoResponse {
-	Html(CREATE_HTML)
+pub async fn get(
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	State(ctx): State<Arc<Context>>,
+) -> impl IntoResponse {
+	let Context { db, .. } = ctx.as_ref();
+	let user = ok!(User::authenticated(db, cookies).await);
+
+	let mut templates = vec![];
+	for ns in Namespace::list_with_access(db, &user, auth::READ).await {
+		templates.extend(
+			Page::list(db, &ns.name)
+				.await
+				.filter(|page| {
+					page.is_template()
+						&& page.user_has_access(&user, &ns.name, auth::READ)
+				})
+				.map(|page| TemplateOption {
+					namespace: ns.name.clone(),
+					slug: page.slug().to_string(),
+					title: page.title().to_string(),
+				}),
+		);
+	}
+
+	Html(CreateTemplate { templates }.render().unwrap()).into_response()
 }
 
 #[axum_macros::debug_handler]
@@ -34,40 +83,181 @@ pub async fn post(
 ) -> impl IntoResponse {
 	let mut user = ok!(User::authenticated(&state.db, cookies).await);
 
-	let ns = {
-		let ns_maybe = ok!(Namespace::get(&state.db, &params.namespace).await);
-		if let Some(ns) = ns_maybe {
-			if !ns.user_has_access(&user, auth::WRITE) {
+	// New namespaces are only stored once the page is known to be valid, so a
+	// rejected create doesn't leave one behind.
+	let (mut ns, new_ns) =
+		match ok!(Namespace::get(&state.db, &params.namespace).await) {
+			Some(ns) if !ns.user_has_access(&user, auth::WRITE) => {
 				return Redirect::to("/create?error=EPERM").into_response();
 			}
-			ns
-		} else if let Some(ref mut user) = user {
+			Some(ns) => (ns, false),
 			// TODO: Any user can create a namespace, but I could see an admin
 			//       not wanting that.
 			// TODO: Also one must be a user to create a namespace, this too may
 			//       not be desirable.
-			let mut new_ns =
-				Namespace::new(&params.namespace, &user.name, 0o755);
-			ok!(add_user_to_namespace(&state.db, user, &mut new_ns).await);
-			new_ns
-		} else {
-			return Redirect::to("/login").into_response();
+			None => match user {
+				Some(ref user) => {
+					(Namespace::new(&params.namespace, &user.name, 0o755), true)
+				}
+				None => return Redirect::to("/login").into_response(),
+			},
+		};
+
+	let requested_mode =
+		match params.mode.trim() {
+			"" => None,
+			mode => Some(ok!(u16::from_str_radix(mode, 8)
+				.map_err(|_| WkError::InvalidArgument))),
+		};
+	let mode = ok!(ns.page_mode(requested_mode));
+
+	let author = user
+		.as_ref()
+		.map(User::name)
+		.unwrap_or("anonymous")
+		.to_string();
+	let content = match params.template.split_once('/') {
+		Some((template_ns, template_slug)) if params.content.is_empty() => {
+			let template =
+				read_template(&state.db, &user, template_ns, template_slug)
+					.await;
+			fill_template(
+				ok!(template).content(),
+				params.title.as_str(),
+				&author,
+			)
 		}
+		_ => params.content,
 	};
 
-	// TODO: Custom perms.
-	// TODO: Sanitize.
-	let page = Page::new(
+	let mut page = Page::new(
 		params.title.as_str(),
-		Page::DEFAULT_MODE - ns.umask,
+		mode,
 		user.as_ref().map(User::name),
 		None,
 	);
+	ok!(page.set_content(&content).map_err(WkError::from));
+	if page.slug().is_empty() {
+		return WkError::InvalidArgument.into_response();
+	}
+	// Subpages hang off an existing page, one the user can see.
+	if let Some(parent) = page.parent_slug() {
+		if !Page::get(&state.db, &ns.name, parent)
+			.await
+			.is_some_and(|parent| {
+				parent.user_has_access(&user, &ns.name, auth::READ)
+			}) {
+			return WkError::InvalidArgument.into_response();
+		}
+	}
+
+	if let Some(existing) = Page::find(&state.db, &ns.name, page.slug()).await {
+		return Redirect::to(&page::url(&ns.name, existing.slug()))
+			.into_response();
+	}
+	if let Some(user) = user.as_mut().filter(|_| new_ns) {
+		ok!(add_user_to_namespace(&state.db, user, &mut ns).await);
+	}
+	ok!(Page::create(&state.db, &ns.name, &mut page).await);
+	ok!(HistoryRecord::append(
+		&state.db,
+		&ns.name,
+		page.slug(),
+		&author,
+		"",
+		page.content(),
+	)
+	.await);
+	ok!(
+		include::record(&state.db, &ns.name, page.slug(), "", page.content())
+			.await
+	);
+
+	let url = page::url(&ns.name, page.slug());
+	state.search.update_index(&ns.name, page).await;
+
+	Redirect::to(&url).into_response()
+}
+
+/// A template page, if the user may read it.
+async fn read_template(
+	db: &TransactionDB,
+	user: &Option<User>,
+	ns: &str,
+	slug: &str,
+) -> Result<Page, WkError> {
+	let ns = Namespace::get(db, ns)
+		.await?
+		.filter(|ns| ns.user_has_access(user, auth::READ))
+		.ok_or(WkError::Access)?;
+	Page::get(db, &ns.name, slug)
+		.await
+		.filter(|page| {
+			page.is_template()
+				&& page.user_has_access(user, &ns.name, auth::READ)
+		})
+		.ok_or(WkError::Access)
+}
+
+/// Fills in the `{{date}}`, `{{author}}` and `{{title}}` placeholders of a
+/// template page. The template's own front matter `title` is dropped, the new
+/// page has the one it was created with.
+fn fill_template(template: &str, title: &str, author: &str) -> String {
+	let filled = template
+		.replace("{{date}}", &OffsetDateTime::now_utc().date().to_string())
+		.replace("{{author}}", author)
+		.replace("{{title}}", title);
+	match front_matter::split(&filled) {
+		(Some(yaml), rest) => {
+			format!("---\n{}---\n{rest}", without_title(yaml))
+		}
+		(None, _) => filled,
+	}
+}
+
+/// Front matter YAML without its top level `title` key, including any lines
+/// its value continues on.
+fn without_title(yaml: &str) -> String {
+	let mut in_title = false;
+	yaml.split_inclusive('\n')
+		.filter(|line| {
+			if !line.starts_with([' ', '\t']) {
+				in_title = line.starts_with("title:");
+			}
+			!in_title
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-	// TODO: Check for duplicates first.
-	Page::put(&state.db, &ns.name, &page).await;
+	#[test]
+	fn templates_drop_their_title() {
+		let template = "---\ntitle: Template:Runbook\ntags: [runbook]\n\
+			owner: {{author}}\n---\n# {{title}}\n";
+		assert_eq!(
+			fill_template(template, "Restoring backups", "ana"),
+			"---\ntags: [runbook]\nowner: ana\n---\n# Restoring backups\n"
+		);
+	}
 
-	state.search.write().unwrap().update_index(&ns.name, &page);
+	#[test]
+	fn templates_drop_titles_spanning_lines() {
+		let template =
+			"---\ntitle: >\n  Template:\n  Runbook\nsummary: x\n---\n";
+		assert_eq!(
+			fill_template(template, "Restoring backups", "ana"),
+			"---\nsummary: x\n---\n"
+		);
+	}
 
-	Redirect::to(&format!("/{}/{}", ns.name, page.slug())).into_response()
+	#[test]
+	fn templates_without_front_matter_are_filled() {
+		assert_eq!(
+			fill_template("By {{author}}", "Restoring backups", "ana"),
+			"By ana"
+		);
+	}
 }
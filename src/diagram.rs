@@ -0,0 +1,109 @@
+use std::{
+	collections::HashMap,
+	panic,
+	sync::{LazyLock, Mutex},
+};
+
+use layout::{
+	backends::svg::SVGWriter,
+	gv::{DotParser, GraphBuilder},
+};
+use sha2::{Digest, Sha256};
+
+use crate::render::escape;
+
+/// Diagrams kept laid out, the cache starts over when it's full.
+const CACHE_SIZE: usize = 256;
+
+/// Longest source laid out, layout time grows much faster than the graph.
+const MAX_SOURCE_BYTES: usize = 16 * 1024;
+
+/// Most nodes laid out.
+const MAX_NODES: usize = 200;
+
+/// Source hash => rendered diagram, so views don't lay graphs out again.
+static CACHE: LazyLock<Mutex<HashMap<String, String>>> =
+	LazyLock::new(Default::default);
+
+/// Lays out Graphviz source from a ```` ```dot ```` block as inline SVG, or an
+/// error box saying why it couldn't.
+pub fn to_svg(source: &str) -> String {
+	let hash = format!("{:x}", Sha256::digest(source));
+	if let Some(diagram) = CACHE.lock().unwrap().get(&hash) {
+		return diagram.clone();
+	}
+
+	let diagram = match layout_svg(source) {
+		// Several diagrams can be on one page, keep their ids apart.
+		Ok(svg) => {
+			format!("<div class=\"diagram\">{}</div>", clean(&svg, &hash[..8]))
+		}
+		Err(e) => format!(
+			"<div class=\"diagram-error\"><p>Can't draw this diagram: {}</p>\
+			 <pre><code>{}</code></pre></div>",
+			escape(&e),
+			escape(source)
+		),
+	};
+
+	let mut cache = CACHE.lock().unwrap();
+	if cache.len() >= CACHE_SIZE {
+		cache.clear();
+	}
+	cache.insert(hash, diagram.clone());
+	diagram
+}
+
+fn layout_svg(source: &str) -> Result<String, String> {
+	if source.len() > MAX_SOURCE_BYTES {
+		return Err(format!("it's over {}KB long.", MAX_SOURCE_BYTES / 1024));
+	}
+	let graph = DotParser::new(source).process()?;
+	// The layout engine panics on some graphs it can parse.
+	panic::catch_unwind(|| {
+		let mut builder = GraphBuilder::new();
+		builder.visit_graph(&graph);
+		let mut visual = builder.get();
+		if visual.num_nodes() > MAX_NODES {
+			return Err(format!("it has over {MAX_NODES} nodes."));
+		}
+		let mut svg = SVGWriter::new();
+		visual.do_it(false, false, false, &mut svg);
+		Ok(svg.finalize())
+	})
+	.map_err(|_| "the layout failed.".to_string())?
+}
+
+/// Makes the SVG fit for inlining: no XML declaration, ids unique to this
+/// diagram, and font sizes as attributes since `<style>` is sanitized away.
+fn clean(svg: &str, prefix: &str) -> String {
+	let svg = svg
+		.split_once("?>")
+		.map_or(svg, |(_, rest)| rest)
+		.replace("id=\"arrow", &format!("id=\"d{prefix}-arrow"))
+		.replace("href=\"#arrow", &format!("href=\"#d{prefix}-arrow"))
+		.replace("id=\"C", &format!("id=\"d{prefix}-C"))
+		.replace("url(#C", &format!("url(#d{prefix}-C"));
+	let svg = match (svg.find("<style>"), svg.find("</style>")) {
+		(Some(start), Some(end)) => {
+			format!("{}{}", &svg[..start], &svg[end + "</style>".len()..])
+		}
+		_ => svg,
+	};
+
+	// `class="a14"` => `font-size="14"`.
+	let mut cleaned = String::with_capacity(svg.len());
+	let mut rest = svg.as_str();
+	while let Some(start) = rest.find("class=\"a") {
+		let size_start = start + "class=\"a".len();
+		let Some(len) = rest[size_start..].find('"') else {
+			break;
+		};
+		cleaned.push_str(&rest[..start]);
+		cleaned.push_str("font-size=\"");
+		cleaned.push_str(&rest[size_start..size_start + len + 1]);
+		rest = &rest[size_start + len + 1..];
+	}
+	cleaned.push_str(rest);
+	cleaned
+}
@@ -10,17 +10,18 @@ use axum_extra::{headers, TypedHeader};
 use serde::Deserialize;
 
 use crate::{
+	attachment::FileLink,
 	auth,
 	auth::{
 		namespace::Namespace,
 		user::{User, UserView},
 	},
-	encoding::{DbDecode, DbEncode},
+	errors::WkError,
 	exists,
-	history::db::{HistoryRecord, HistoryVersionRecord},
-	not_found, ok,
+	history::db::HistoryRecord,
+	include, not_found, ok, page,
 	page::Page,
-	Context, HIST_CF,
+	Context,
 };
 
 #[derive(Template)]
@@ -28,14 +29,25 @@ use crate::{
 struct EditTemplate {
 	title: String,
 	content: String,
-	namespace: String,
-	slug: String,
+	tags: String,
+	url: String,
+	files: Vec<FileLink>,
+	/// Why the last save was rejected, its content is kept in the form.
+	error: Option<String>,
 	user: Option<UserView>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EditPayload {
 	content: String,
+	/// Comma separated.
+	#[serde(default)]
+	tags: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewPayload {
+	content: String,
 }
 
 #[axum_macros::debug_handler]
@@ -62,9 +74,15 @@ pub async fn get(
 			EditTemplate {
 				title: page.title().to_string(),
 				content: page.content().to_string(),
+				tags: page.edited_tags().join(", "),
 				user: user.map(UserView::new),
-				namespace: ns.name,
-				slug: page.slug().to_string(),
+				url: page::url(&ns.name, page.slug()),
+				files: page
+					.attachments()
+					.iter()
+					.map(|file| FileLink::new(&ns.name, page.slug(), file))
+					.collect(),
+				error: None,
 			}
 			.render()
 			.unwrap(),
@@ -95,50 +113,91 @@ pub async fn post(
 	if let Some(mut page) = Page::get(db, &ns.name, &slug).await {
 		if !page.user_has_access(&user, &ns.name, auth::WRITE) {
 			return Redirect::to(&format!(
-				"/{}/{slug}/edit?error=EPERM",
-				&ns.name
+				"{}/edit?error=EPERM",
+				page::url(&ns.name, &slug)
 			))
 			.into_response();
 		}
 
-		// TODO: Move this.
-		let tx = db.transaction();
-		let version_key = HistoryVersionRecord::key(&ns.name, &slug);
-		let version = tx
-			// TODO: Brittle.
-			.get_cf(&db.cf_handle(HIST_CF).unwrap(), &version_key)
-			.unwrap()
-			.map(HistoryVersionRecord::dec)
-			.unwrap_or(HistoryVersionRecord::default());
-		tx.put_cf(
-			&db.cf_handle(HIST_CF).unwrap(),
-			version_key,
-			version.next().enc(),
-		)
-		.unwrap();
-		tx.put_cf(
-			&db.cf_handle(HIST_CF).unwrap(),
-			HistoryRecord::key(&ns.name, &slug, version),
-			HistoryRecord::new(
-				&user.map(|u| u.name).unwrap_or("anonymous".to_string()),
-				page.content(),
-				params.content.as_str(),
+		let previous = page.content().to_string();
+		if let Err(e) = page.set_content(params.content.as_str()) {
+			// Don't lose the edit over a typo in the front matter.
+			return Html(
+				EditTemplate {
+					title: page.title().to_string(),
+					url: page::url(&ns.name, page.slug()),
+					files: page
+						.attachments()
+						.iter()
+						.map(|file| FileLink::new(&ns.name, page.slug(), file))
+						.collect(),
+					content: params.content,
+					tags: params.tags,
+					error: Some(e.to_string()),
+					user: user.map(UserView::new),
+				}
+				.render()
+				.unwrap(),
 			)
-			.enc(),
+			.into_response();
+		}
+		page.set_tags(params.tags.split(','));
+
+		ok!(HistoryRecord::append(
+			db,
+			&ns.name,
+			&slug,
+			user.as_ref().map(User::name).unwrap_or("anonymous"),
+			&previous,
+			page.content(),
 		)
-		.unwrap();
-		tx.commit().unwrap();
+		.await);
+		ok!(
+			include::record(db, &ns.name, &slug, &previous, page.content())
+				.await
+		);
 
-		// TODO: Sanitize.
-		page.set_content(params.content.as_str());
+		ok!(Page::put(db, &ns.name, &mut page).await);
 
-		// TODO: Handle DB error.
-		Page::put(db, &ns.name, &page).await;
+		search.update_index(&ns.name, page).await;
 
-		search.write().unwrap().update_index(&ns.name, &page);
-
-		Redirect::to(&format!("/{}/{slug}", &ns.name)).into_response()
+		Redirect::to(&page::url(&ns.name, &slug)).into_response()
 	} else {
 		not_found().await.into_response()
 	}
 }
+
+/// The page as it would look with some content, for the edit form.
+#[axum_macros::debug_handler]
+pub async fn preview(
+	Path((ns, slug)): Path<(String, String)>,
+	State(ctx): State<Arc<Context>>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	Form(params): Form<PreviewPayload>,
+) -> impl IntoResponse {
+	let Context { db, .. } = ctx.as_ref();
+
+	let user = ok!(User::authenticated(db, cookies).await);
+	let ns = exists!(ok!(Namespace::get(db, &ns).await));
+	if !ns.user_has_access(&user, auth::READ) {
+		return not_found().await.into_response();
+	}
+
+	let mut page = exists!(Page::get(db, &ns.name, &slug)
+		.await
+		.filter(|page| page.user_has_access(&user, &ns.name, auth::READ)));
+	// Previewing is part of editing.
+	if !page.user_has_access(&user, &ns.name, auth::WRITE) {
+		return Html(format!(
+			"<p><em>Can't preview: {}</em></p>",
+			WkError::Access
+		))
+		.into_response();
+	}
+	if let Err(e) = page.set_content(&params.content) {
+		return Html(format!("<p><em>Can't preview: {e}</em></p>"))
+			.into_response();
+	}
+
+	Html(include::render(db, &user, &ns, &page).await.html).into_response()
+}
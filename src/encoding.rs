@@ -1,11 +1,15 @@
-use crate::BINCODE_CONFIG;
+use crate::{errors::WkError, BINCODE_CONFIG};
 
 pub trait DbEncode {
 	fn enc(&self) -> Vec<u8>;
 }
 
-pub trait DbDecode {
+pub trait DbDecode: Sized {
 	fn dec<B: AsRef<[u8]>>(bytes: B) -> Self;
+
+	/// Like `dec`, but fails on bytes that aren't exactly one `Self`, e.g. a
+	/// record stored in an older format.
+	fn try_dec<B: AsRef<[u8]>>(bytes: B) -> Result<Self, WkError>;
 }
 
 impl<T> DbEncode for T
@@ -29,4 +33,15 @@ where
 			bincode::decode_from_slice(bytes.as_ref(), BINCODE_CONFIG).unwrap();
 		me
 	}
+
+	fn try_dec<B>(bytes: B) -> Result<Self, WkError>
+	where
+		B: AsRef<[u8]>,
+	{
+		let bytes = bytes.as_ref();
+		match bincode::decode_from_slice(bytes, BINCODE_CONFIG) {
+			Ok((me, len)) if len == bytes.len() => Ok(me),
+			_ => Err(WkError::Corrupt),
+		}
+	}
 }
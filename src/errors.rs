@@ -15,6 +15,8 @@ pub enum WkError {
 	Io, // EIO
 	#[error("Invalid argument.")]
 	InvalidArgument, // EINVAL
+	#[error("Already exists.")]
+	Exists, // EEXIST
 }
 
 impl IntoResponse for WkError {
@@ -23,9 +25,11 @@ impl IntoResponse for WkError {
 			Self::Auth => {
 				Redirect::to(&format!("/login?error={self}")).into_response()
 			}
-			Self::Access | Self::Corrupt | Self::Io | Self::InvalidArgument => {
-				Redirect::to(&format!("?error={self}")).into_response()
-			}
+			Self::Access
+			| Self::Corrupt
+			| Self::Io
+			| Self::InvalidArgument
+			| Self::Exists => Redirect::to(&format!("?error={self}")).into_response(),
 		}
 	}
 }
@@ -36,6 +40,12 @@ impl From<rocksdb::Error> for WkError {
 	}
 }
 
+impl From<tantivy::TantivyError> for WkError {
+	fn from(_: tantivy::TantivyError) -> Self {
+		WkError::Io
+	}
+}
+
 /// Unwraps a `Result<T>` or returns an HTTP error response.
 #[macro_export]
 macro_rules! ok {
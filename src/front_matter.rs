@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+
+use bincode::{Decode, Encode};
+use serde::Deserialize;
+use thiserror::Error;
+use time::{macros::format_description, Date};
+
+use crate::{errors::WkError, page};
+
+/// Metadata from a YAML block at the top of a page, e.g.
+///
+/// ```text
+/// ---
+/// title: Restoring backups
+/// tags: [runbook, database] # or runbook, database
+/// summary: Getting a database back from last night's snapshot.
+/// owner: dba@example.com
+/// review-by: 2026-06-01
+/// pager: db-oncall
+/// ---
+/// ```
+#[derive(Encode, Decode, Debug, Default)]
+pub struct PageMeta {
+	pub title: Option<String>,
+	pub tags: Vec<String>,
+	pub summary: Option<String>,
+	pub contact: Option<String>,
+	/// YYYY-MM-DD.
+	pub review_by: Option<String>,
+	/// Everything else, with non-string values written back as YAML.
+	pub extra: BTreeMap<String, String>,
+}
+
+/// Why front matter was rejected, shown when editing.
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("Front matter isn't valid YAML: {0}")]
+	Yaml(String),
+	#[error("review-by should be YYYY-MM-DD, not {0:?}")]
+	ReviewBy(String),
+}
+
+impl From<Error> for WkError {
+	fn from(_: Error) -> Self {
+		WkError::InvalidArgument
+	}
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FrontMatter {
+	title: Option<String>,
+	tags: Tags,
+	summary: Option<String>,
+	#[serde(alias = "owner")]
+	contact: Option<String>,
+	#[serde(alias = "review-by")]
+	review_by: Option<String>,
+	#[serde(flatten)]
+	extra: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// A list of tags, or one string of them comma separated, as on the edit page.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tags {
+	List(Vec<String>),
+	Scalar(String),
+}
+
+impl Default for Tags {
+	fn default() -> Self {
+		Self::List(vec![])
+	}
+}
+
+impl Tags {
+	fn iter(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+		match self {
+			Self::List(tags) => Box::new(tags.iter().map(String::as_str)),
+			Self::Scalar(tags) => Box::new(tags.split(',')),
+		}
+	}
+}
+
+impl PageMeta {
+	/// Parses the front matter of page content, if it has any.
+	pub fn parse(content: &str) -> Result<Self, Error> {
+		let yaml = match split(content) {
+			(Some(yaml), _) if !yaml.trim().is_empty() => yaml,
+			_ => return Ok(Self::default()),
+		};
+		let front_matter = serde_yaml::from_str::<FrontMatter>(yaml)
+			.map_err(|e| Error::Yaml(e.to_string()))?;
+
+		if let Some(ref date) = front_matter.review_by {
+			parse_date(date).ok_or_else(|| Error::ReviewBy(date.clone()))?;
+		}
+
+		Ok(Self {
+			title: front_matter.title,
+			tags: front_matter
+				.tags
+				.iter()
+				.map(page::normalize_tag)
+				.filter(|tag| !tag.is_empty())
+				.collect(),
+			summary: front_matter.summary,
+			contact: front_matter.contact,
+			review_by: front_matter.review_by,
+			extra: front_matter
+				.extra
+				.into_iter()
+				.map(|(key, value)| (key, yaml_to_string(value)))
+				.collect(),
+		})
+	}
+}
+
+/// Splits a leading `---` delimited YAML block off of page content, returning
+/// the YAML and the rest of the content.
+pub fn split(content: &str) -> (Option<&str>, &str) {
+	let Some(rest) = content
+		.strip_prefix("---\n")
+		.or_else(|| content.strip_prefix("---\r\n"))
+	else {
+		return (None, content);
+	};
+
+	let mut offset = 0;
+	for line in rest.split_inclusive('\n') {
+		if line.trim_end() == "---" {
+			return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+		}
+		offset += line.len();
+	}
+
+	// Never closed, so it's just a horizontal rule.
+	(None, content)
+}
+
+pub fn parse_date(date: &str) -> Option<Date> {
+	Date::parse(date.trim(), format_description!("[year]-[month]-[day]")).ok()
+}
+
+fn yaml_to_string(value: serde_yaml::Value) -> String {
+	match value {
+		serde_yaml::Value::String(s) => s,
+		other => serde_yaml::to_string(&other)
+			.unwrap_or_default()
+			.trim()
+			.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn split_takes_the_yaml_off() {
+		assert_eq!(
+			split("---\ntitle: A\n---\nBody"),
+			(Some("title: A\n"), "Body")
+		);
+		assert_eq!(
+			split("---\r\ntitle: A\r\n---\r\nBody"),
+			(Some("title: A\r\n"), "Body")
+		);
+		assert_eq!(split("---\n---\nBody"), (Some(""), "Body"));
+	}
+
+	#[test]
+	fn split_leaves_content_without_front_matter() {
+		assert_eq!(split("Body\n---\n"), (None, "Body\n---\n"));
+		// Never closed, a horizontal rule.
+		assert_eq!(split("---\nBody"), (None, "---\nBody"));
+	}
+
+	#[test]
+	fn parse_reads_fields() {
+		let meta = PageMeta::parse(
+			"---\ntitle: Restoring backups\ntags: [Runbook, Incident Reports]\n\
+			 owner: dba@example.com\nreview-by: 2026-06-01\npager: db-oncall\n\
+			 retries: 3\n---\nBody",
+		)
+		.unwrap();
+		assert_eq!(meta.title.as_deref(), Some("Restoring backups"));
+		assert_eq!(meta.tags, ["runbook", "incident-reports"]);
+		assert_eq!(meta.contact.as_deref(), Some("dba@example.com"));
+		assert_eq!(meta.review_by.as_deref(), Some("2026-06-01"));
+		assert_eq!(meta.extra["pager"], "db-oncall");
+		assert_eq!(meta.extra["retries"], "3");
+	}
+
+	#[test]
+	fn parse_reads_scalar_tags() {
+		let meta = PageMeta::parse("---\ntags: runbook\n---\n").unwrap();
+		assert_eq!(meta.tags, ["runbook"]);
+		let meta =
+			PageMeta::parse("---\ntags: runbook, Database,\n---\n").unwrap();
+		assert_eq!(meta.tags, ["runbook", "database"]);
+	}
+
+	#[test]
+	fn parse_without_front_matter() {
+		let meta = PageMeta::parse("Body").unwrap();
+		assert!(meta.title.is_none() && meta.tags.is_empty());
+	}
+
+	#[test]
+	fn parse_rejects_bad_front_matter() {
+		assert!(matches!(
+			PageMeta::parse("---\ntags: [runbook\n---\n"),
+			Err(Error::Yaml(_))
+		));
+		assert!(matches!(
+			PageMeta::parse("---\nreview-by: next week\n---\n"),
+			Err(Error::ReviewBy(date)) if date == "next week"
+		));
+	}
+}
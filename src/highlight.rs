@@ -0,0 +1,80 @@
+use std::{collections::HashMap, io, io::Write, sync::LazyLock};
+
+use comrak::{adapters::SyntaxHighlighterAdapter, html};
+use syntect::{
+	html::{ClassStyle, ClassedHTMLGenerator},
+	parsing::{SyntaxReference, SyntaxSet},
+	util::LinesWithEndings,
+};
+
+/// Highlighted code is marked up with classes like `hl-keyword`, styled in
+/// `main.css`.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Loading the syntaxes takes a while, so it's done once.
+static SYNTAXES: LazyLock<SyntaxSet> =
+	LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// Highlights fenced code blocks by their info string, e.g. ```` ```sql ````.
+pub struct Highlighter;
+
+impl Highlighter {
+	fn syntax(lang: &str) -> Option<&'static SyntaxReference> {
+		// Names people use that syntect doesn't know.
+		let lang = match lang.to_lowercase().as_str() {
+			"shell" | "console" | "zsh" => "bash".to_string(),
+			"postgres" | "postgresql" | "psql" | "mysql" => "sql".to_string(),
+			lang => lang.to_string(),
+		};
+		SYNTAXES.find_syntax_by_token(&lang)
+	}
+
+	fn highlight(
+		syntax: &SyntaxReference,
+		code: &str,
+	) -> Result<String, syntect::Error> {
+		let mut generator = ClassedHTMLGenerator::new_with_class_style(
+			syntax,
+			&SYNTAXES,
+			CLASS_STYLE,
+		);
+		for line in LinesWithEndings::from(code) {
+			generator.parse_html_for_line_which_includes_newline(line)?;
+		}
+		Ok(generator.finalize())
+	}
+}
+
+impl SyntaxHighlighterAdapter for Highlighter {
+	fn write_highlighted(
+		&self,
+		output: &mut dyn Write,
+		lang: Option<&str>,
+		code: &str,
+	) -> io::Result<()> {
+		let highlighted = lang
+			.and_then(Self::syntax)
+			.and_then(|syntax| Self::highlight(syntax, code).ok());
+		match highlighted {
+			Some(highlighted) => output.write_all(highlighted.as_bytes()),
+			None => html::escape(output, code.as_bytes()),
+		}
+	}
+
+	fn write_pre_tag(
+		&self,
+		output: &mut dyn Write,
+		mut attributes: HashMap<String, String>,
+	) -> io::Result<()> {
+		attributes.insert("class".to_string(), "highlight".to_string());
+		html::write_opening_tag(output, "pre", attributes)
+	}
+
+	fn write_code_tag(
+		&self,
+		output: &mut dyn Write,
+		attributes: HashMap<String, String>,
+	) -> io::Result<()> {
+		html::write_opening_tag(output, "code", attributes)
+	}
+}
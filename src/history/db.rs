@@ -1,6 +1,12 @@
 use bincode::{Decode, Encode};
+use rocksdb::TransactionDB;
 
-use crate::history::delta::Delta;
+use crate::{
+	encoding::{DbDecode, DbEncode},
+	errors::WkError,
+	history::delta::Delta,
+	HIST_CF,
+};
 
 /// namespace/page-name/1       = HistoryRecord { ... }
 /// namespace/page-name/0       = HistoryRecord { ... }
@@ -29,6 +35,33 @@ impl HistoryRecord {
 			delta: Delta::new(author, old, new),
 		}
 	}
+
+	/// Records the change from `old` to `new` as the next revision of a page.
+	pub async fn append(
+		db: &TransactionDB,
+		ns: &str,
+		slug: &str,
+		author: &str,
+		old: &str,
+		new: &str,
+	) -> Result<(), WkError> {
+		let cf = db.cf_handle(HIST_CF).unwrap();
+		let tx = db.transaction();
+		let version_key = HistoryVersionRecord::key(ns, slug);
+		let version = tx
+			.get_for_update_cf(&cf, &version_key, true)?
+			.map(HistoryVersionRecord::dec)
+			.unwrap_or_default();
+		tx.put_cf(&cf, version_key, version.next().enc())?;
+		tx.put_cf(
+			&cf,
+			Self::key(ns, slug, version),
+			Self::new(author, old, new).enc(),
+		)?;
+		tx.commit()?;
+
+		Ok(())
+	}
 }
 
 impl HistoryVersionRecord {
@@ -45,7 +78,7 @@ impl HistoryVersionRecord {
 
 impl HistoryKey {
 	pub fn revision(&self) -> String {
-		self.0.split('/').last().unwrap().to_string()
+		self.0.split('/').next_back().unwrap().to_string()
 	}
 
 	pub fn from_bytes<B>(bytes: B) -> Self
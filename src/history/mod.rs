@@ -15,7 +15,7 @@ use crate::{
 		db::{HistoryKey, HistoryRecord},
 		view::{HistoryRevisionView, HistoryView},
 	},
-	ok,
+	ok, page,
 	page::Page,
 	Context, HIST_CF,
 };
@@ -44,10 +44,12 @@ pub async fn get(
 		// Retrieve history as database records.
 		.filter_map(|(k, v)| {
 			let history_key = String::from_utf8(k.to_vec()).unwrap();
+			let revision = history_key.strip_prefix(&format!("{}/", &key));
 			if history_key.contains("VERSION")
-				|| !history_key.starts_with(&format!("{}/", &key))
+				|| revision.is_none_or(|revision| revision.contains('/'))
 			{
-				// Skip VERSION records, ... or prefixes that match this slug?
+				// Skip VERSION records, prefixes that match this slug, and
+				// subpages.
 				None
 			} else {
 				Some((HistoryKey(history_key), HistoryRecord::dec(v)))
@@ -63,7 +65,7 @@ pub async fn get(
 	Html(
 		HistoryView {
 			title: page.title(),
-			slug: slug.as_str(),
+			url: page::url(&ns, page.slug()),
 			revisions: versions,
 			user: user.map(UserView::new),
 		}
@@ -11,7 +11,7 @@ use crate::{
 #[derive(Template)]
 #[template(path = "history.html")]
 pub struct HistoryView<'a> {
-	pub slug: &'a str,
+	pub url: String,
 	pub title: &'a str,
 	pub revisions: Vec<HistoryRevisionView>,
 	pub user: Option<UserView>,
@@ -0,0 +1,399 @@
+use std::{collections::BTreeSet, ops::Range};
+
+use bincode::{Decode, Encode};
+use futures::future::BoxFuture;
+use rocksdb::{IteratorMode, Transaction, TransactionDB};
+
+use crate::{
+	attachment, auth,
+	auth::{namespace::Namespace, user::User},
+	encoding::{DbDecode, DbEncode},
+	errors::WkError,
+	page,
+	page::Page,
+	render,
+	render::Rendered,
+	INCL_CF,
+};
+
+const OPEN: &str = "{{include ";
+const CLOSE: &str = "}}";
+
+/// Longest chain of includes followed, e.g. A includes B includes C is 2.
+const MAX_DEPTH: usize = 4;
+
+/// Most includes expanded in one page, however they're nested.
+const MAX_INCLUDES: usize = 50;
+
+/// Longest a page gets with its includes expanded.
+const MAX_BYTES: usize = 1 << 20;
+
+/// ns/slug => pages that include it, as ns/slug.
+#[derive(Encode, Decode, Default, Debug)]
+pub struct IncludedBy(BTreeSet<String>);
+
+/// What expanding a page has used up so far, so a page that includes others
+/// many times over can't make views arbitrarily slow.
+#[derive(Default)]
+struct Budget {
+	/// Pages being expanded, as ns/slug, outermost first.
+	stack: Vec<String>,
+	includes: usize,
+	bytes: usize,
+	/// Names of all namespaces, see `resolve`.
+	namespaces: BTreeSet<String>,
+}
+
+/// Renders a page, replacing each `{{include ns:Page}}` in its body with that
+/// page, if the viewer may read it. `{{include Page}}` is in the same
+/// namespace. Included pages are rendered the way their own namespace renders
+/// them, so its HTML policy and extensions hold wherever they're shown.
+/// `file:` links are pointed at the attachments of the page they were written
+/// in.
+pub async fn render(
+	db: &TransactionDB,
+	user: &Option<User>,
+	ns: &Namespace,
+	page: &Page,
+) -> Rendered {
+	let mut budget = Budget {
+		stack: vec![format!("{}/{}", ns.name, page.slug())],
+		namespaces: namespaces(db).await,
+		..Default::default()
+	};
+	render_page(db, user, ns, page, &mut budget).await
+}
+
+fn render_page<'a>(
+	db: &'a TransactionDB,
+	user: &'a Option<User>,
+	ns: &'a Namespace,
+	page: &'a Page,
+	budget: &'a mut Budget,
+) -> BoxFuture<'a, Rendered> {
+	Box::pin(async move {
+		let body = &attachment::link_files(&ns.name, page.slug(), page.body());
+		let mut markdown = String::with_capacity(body.len());
+		let mut included = vec![];
+		let mut last = 0;
+		for (range, target) in includes(body) {
+			markdown.push_str(&body[last..range.start]);
+			budget.bytes += range.start - last;
+			markdown.push_str(&render::placeholder(included.len()));
+			included.push(include(db, user, &ns.name, target, budget).await);
+			last = range.end;
+		}
+		markdown.push_str(&body[last..]);
+		budget.bytes += body.len() - last;
+		// Highlighting and diagram layout are slow, keep them off the async
+		// workers.
+		let options = ns.markdown.clone();
+		tokio::task::spawn_blocking(move || {
+			render::render(&markdown, &options, &included)
+		})
+		.await
+		// Panics: Only if rendering did.
+		.unwrap()
+	})
+}
+
+async fn include(
+	db: &TransactionDB,
+	user: &Option<User>,
+	ns: &str,
+	target: &str,
+	budget: &mut Budget,
+) -> String {
+	let (target_ns, slug) = resolve(ns, target, &budget.namespaces);
+	let key = format!("{target_ns}/{slug}");
+	if budget.stack.contains(&key) {
+		return unavailable(target, "it includes this page");
+	}
+	if budget.stack.len() > MAX_DEPTH {
+		return unavailable(target, "too many nested includes");
+	}
+	if budget.includes >= MAX_INCLUDES {
+		return unavailable(target, "too many includes");
+	}
+	if budget.bytes >= MAX_BYTES {
+		return unavailable(target, "the page is too long");
+	}
+	budget.includes += 1;
+
+	// Pages the viewer can't read look the same as pages that don't exist.
+	let readable = match Namespace::get(db, &target_ns).await {
+		Ok(Some(target_ns)) if target_ns.user_has_access(user, auth::READ) => {
+			Page::get(db, &target_ns.name, &slug)
+				.await
+				.filter(|page| {
+					page.user_has_access(user, &target_ns.name, auth::READ)
+				})
+				.map(|page| (target_ns, page))
+		}
+		_ => None,
+	};
+	let Some((target_ns, page)) = readable else {
+		return unavailable(target, "not found");
+	};
+
+	budget.stack.push(key);
+	let rendered = render_page(db, user, &target_ns, &page, budget).await;
+	budget.stack.pop();
+	rendered.html
+}
+
+fn unavailable(target: &str, reason: &str) -> String {
+	format!(
+		"<em>Can't include {}: {reason}.</em>",
+		render::escape(target)
+	)
+}
+
+/// Includes in some text as (where the whole `{{include ...}}` is, target).
+/// Includes in code are left alone.
+fn includes(text: &str) -> Vec<(Range<usize>, &str)> {
+	let code = render::code_ranges(text);
+	let mut found = vec![];
+	let mut from = 0;
+	while let Some(start) = text[from..].find(OPEN).map(|i| from + i) {
+		let Some(len) = text[start..].find(CLOSE) else {
+			break;
+		};
+		let end = start + len + CLOSE.len();
+		if code.iter().any(|range| range.contains(&start)) {
+			from = start + OPEN.len();
+			continue;
+		}
+
+		found.push((start..end, text[start + OPEN.len()..start + len].trim()));
+		from = end;
+	}
+	found
+}
+
+/// "ns:Page title" => ("ns", "Page_title"), "Page" => (`ns`, "Page"). Only
+/// prefixes naming one of `namespaces` are namespaces, so titles with a colon
+/// like "Template:Runbook" stay in `ns`.
+fn resolve(
+	ns: &str,
+	target: &str,
+	namespaces: &BTreeSet<String>,
+) -> (String, String) {
+	match target.split_once(':') {
+		Some((target_ns, title)) if namespaces.contains(target_ns) => {
+			(target_ns.to_string(), page::slugify(title))
+		}
+		_ => (ns.to_string(), page::slugify(target)),
+	}
+}
+
+async fn namespaces(db: &TransactionDB) -> BTreeSet<String> {
+	Namespace::list(db)
+		.await
+		.into_iter()
+		.map(|ns| ns.name)
+		.collect()
+}
+
+/// Pages included by some content, as ns/slug.
+fn targets(
+	ns: &str,
+	content: &str,
+	namespaces: &BTreeSet<String>,
+) -> BTreeSet<String> {
+	includes(content)
+		.into_iter()
+		.map(|(_, target)| {
+			let (target_ns, slug) = resolve(ns, target, namespaces);
+			format!("{target_ns}/{slug}")
+		})
+		.collect()
+}
+
+/// Updates the dependency index after a page's content changes from `old` to
+/// `new`.
+pub async fn record(
+	db: &TransactionDB,
+	ns: &str,
+	slug: &str,
+	old: &str,
+	new: &str,
+) -> Result<(), WkError> {
+	let namespaces = namespaces(db).await;
+	let before = targets(ns, old, &namespaces);
+	let after = targets(ns, new, &namespaces);
+	if before == after {
+		return Ok(());
+	}
+
+	let includer = format!("{ns}/{slug}");
+	let cf = db.cf_handle(INCL_CF).unwrap();
+	let tx = db.transaction();
+	for target in before.symmetric_difference(&after) {
+		let mut included_by = tx
+			.get_for_update_cf(&cf, target, true)?
+			.map(IncludedBy::dec)
+			.unwrap_or_default();
+		if after.contains(target) {
+			included_by.0.insert(includer.clone());
+		} else {
+			included_by.0.remove(&includer);
+		}
+
+		if included_by.0.is_empty() {
+			tx.delete_cf(&cf, target)?;
+		} else {
+			tx.put_cf(&cf, target, included_by.enc())?;
+		}
+	}
+	tx.commit()?;
+
+	Ok(())
+}
+
+/// Moves a page's entries from one ns/slug to another, both the pages that
+/// include it and where it includes others.
+pub fn rename(
+	db: &TransactionDB,
+	tx: &Transaction<'_, TransactionDB>,
+	from: &str,
+	to: &str,
+) -> Result<(), WkError> {
+	let cf = db.cf_handle(INCL_CF).unwrap();
+	for entry in db.full_iterator_cf(&cf, IteratorMode::Start) {
+		let (key, value) = entry?;
+		let mut included_by = IncludedBy::dec(value);
+		let includes = included_by.0.remove(from);
+		if includes {
+			included_by.0.insert(to.to_string());
+		}
+		if *key == *from.as_bytes() {
+			tx.delete_cf(&cf, &key)?;
+			tx.put_cf(&cf, to, included_by.enc())?;
+		} else if includes {
+			tx.put_cf(&cf, &key, included_by.enc())?;
+		}
+	}
+	Ok(())
+}
+
+/// Pages that include a page, as (ns, slug).
+pub async fn used_by(
+	db: &TransactionDB,
+	ns: &str,
+	slug: &str,
+) -> Result<Vec<(String, String)>, WkError> {
+	let cf = db.cf_handle(INCL_CF).unwrap();
+	let included_by = db
+		.get_cf(&cf, format!("{ns}/{slug}"))?
+		.map(IncludedBy::dec)
+		.unwrap_or_default();
+
+	Ok(included_by
+		.0
+		.into_iter()
+		.filter_map(|key| {
+			key.split_once('/')
+				.map(|(ns, slug)| (ns.to_string(), slug.to_string()))
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::render::MarkdownOptions;
+
+	#[test]
+	fn includes_are_found() {
+		let text = "{{include Disk checks}} and {{include ops:Paging }}";
+		let found = includes(text);
+		assert_eq!(
+			found.iter().map(|(_, target)| *target).collect::<Vec<_>>(),
+			["Disk checks", "ops:Paging"]
+		);
+		assert_eq!(&text[found[0].0.clone()], "{{include Disk checks}}");
+	}
+
+	#[test]
+	fn includes_in_code_are_left() {
+		let text = "`{{include A}}`\n\n```\n{{include B}}\n```\n{{include C}}";
+		let found = includes(text);
+		assert_eq!(
+			found.iter().map(|(_, target)| *target).collect::<Vec<_>>(),
+			["C"]
+		);
+	}
+
+	#[test]
+	fn unclosed_includes_are_left() {
+		assert!(includes("{{include A").is_empty());
+	}
+
+	#[test]
+	fn included_pages_keep_their_namespace_html_policy() {
+		let escaping = MarkdownOptions::default();
+		let raw_html = MarkdownOptions {
+			raw_html: true,
+			..Default::default()
+		};
+		// How `include` renders a page from a namespace that escapes HTML.
+		let included = render::render(
+			"<b>bold</b> <iframe src=\"x\"></iframe>",
+			&escaping,
+			&[],
+		)
+		.html;
+		let rendered = render::render(
+			&format!("<i>mine</i>\n\n{}\n", render::placeholder(0)),
+			&raw_html,
+			&[included],
+		);
+		assert!(rendered.html.contains("<i>mine</i>"));
+		assert!(rendered.html.contains("&lt;b&gt;bold&lt;/b&gt;"));
+		assert!(rendered.html.contains("&lt;iframe"));
+		assert!(!rendered.html.contains("<b>"));
+	}
+
+	#[test]
+	fn included_pages_go_in_place() {
+		let included = ["<p>A</p>".to_string(), "<em>B</em>".to_string()];
+		let markdown = format!(
+			"{}\n\nsee {} here",
+			render::placeholder(0),
+			render::placeholder(1)
+		);
+		let rendered =
+			render::render(&markdown, &MarkdownOptions::default(), &included);
+		assert_eq!(
+			rendered.html,
+			"<div><p>A</p></div>\n<p>see <em>B</em> here</p>\n"
+		);
+	}
+
+	#[test]
+	fn resolve_reads_existing_namespaces() {
+		let namespaces = BTreeSet::from(["ops".to_string()]);
+		assert_eq!(
+			resolve("wiki", "ops:disk checks", &namespaces),
+			("ops".to_string(), "Disk_checks".to_string())
+		);
+		assert_eq!(
+			resolve("wiki", "disk checks", &namespaces),
+			("wiki".to_string(), "Disk_checks".to_string())
+		);
+	}
+
+	#[test]
+	fn resolve_keeps_colons_in_titles() {
+		let namespaces = BTreeSet::from(["ops".to_string()]);
+		assert_eq!(
+			resolve("wiki", "Template:Runbook", &namespaces),
+			("wiki".to_string(), "Template:Runbook".to_string())
+		);
+		assert_eq!(
+			resolve("wiki", ":Runbook", &namespaces),
+			("wiki".to_string(), ":Runbook".to_string())
+		);
+	}
+}
@@ -1,13 +1,13 @@
-use std::{
-	fs,
-	path::Path,
-	sync::{Arc, RwLock},
-};
+use std::{fs, path::Path, sync::Arc};
 
 use axum::{
-	extract::State, http::StatusCode, response::IntoResponse, routing, Router,
+	extract::{DefaultBodyLimit, State},
+	http::StatusCode,
+	response::IntoResponse,
+	routing, Router,
 };
 use rocksdb::{IteratorMode, TransactionDB, TransactionDBOptions};
+use tokio::signal;
 use tower_http::services::ServeDir;
 use tracing::info;
 
@@ -19,19 +19,30 @@ use crate::{
 	},
 	encoding::DbDecode,
 	history::db::{HistoryKey, HistoryVersionRecord},
+	include::IncludedBy,
 	page::{Page, PageKey},
 };
 
+mod attachment;
 mod auth;
 mod control;
 mod create;
+mod diagram;
 mod edit;
 mod encoding;
 mod errors;
+mod front_matter;
+mod highlight;
 mod history;
+mod include;
 mod index;
+mod math;
 mod page;
+mod render;
+mod route;
 mod search;
+mod thumbnail;
+mod upgrade;
 mod view;
 
 include!(concat!(env!("OUT_DIR"), "/config.rs"));
@@ -44,13 +55,17 @@ const PAGE_CF: &str = "page";
 const HIST_CF: &str = "hist";
 const NSPC_CF: &str = "nspc";
 const USER_CF: &str = "user";
+const INCL_CF: &str = "incl";
+const BLOB_CF: &str = "blob";
+const SEQN_CF: &str = "seqn";
+const PEND_CF: &str = "pend";
 
 pub struct Context {
 	// Database.
-	db: TransactionDB,
+	db: Arc<TransactionDB>,
 
 	// Searching.
-	search: RwLock<search::SearchContext>,
+	search: search::SearchContext,
 }
 
 #[tokio::main]
@@ -65,9 +80,16 @@ async fn main() {
 		&db_opts,
 		&TransactionDBOptions::default(),
 		LOCAL_DB_PATH,
-		vec![PAGE_CF, HIST_CF, USER_CF, NSPC_CF],
+		vec![
+			PAGE_CF, HIST_CF, USER_CF, NSPC_CF, INCL_CF, BLOB_CF, SEQN_CF,
+			PEND_CF,
+		],
 	)
 	.unwrap();
+	let db = Arc::new(db);
+
+	// Panics: We're initializing, so prefer to crash here.
+	upgrade::run(&db).await.unwrap();
 
 	// Populate meta namespace.
 	// TODO: This really doesn't need to happen every time the application
@@ -76,8 +98,9 @@ async fn main() {
 	seed_base(&db).await;
 
 	// Search
-	info!("Building search index");
-	let search_context = RwLock::new(search::SearchContext::new(&db).await);
+	info!("Opening search index: {LOCAL_INDEX_PATH}");
+	let search_context =
+		search::SearchContext::new(db.clone(), LOCAL_INDEX_PATH).await;
 
 	// Whole world.
 	let context = Arc::new(Context {
@@ -89,12 +112,17 @@ async fn main() {
 	let app = Router::new()
 		.route("/", routing::get(index::get))
 		.route("/search", routing::get(search::get))
+		.route("/search/suggest", routing::get(search::suggest))
+		.route("/search/metrics", routing::get(search::metrics))
+		.route("/tags/:tag", routing::get(search::tag))
 		.route("/create", routing::get(create::get))
 		.route("/create", routing::post(create::post))
-		.route("/:ns/:slug", routing::get(view::get))
-		.route("/:ns/:slug/history", routing::get(history::get))
-		.route("/:ns/:slug/edit", routing::get(edit::get))
-		.route("/:ns/:slug/edit", routing::post(edit::post))
+		.route("/:ns/*path", routing::get(route::get))
+		.route(
+			"/:ns/*path",
+			routing::post(route::post)
+				.layer(DefaultBodyLimit::max(attachment::MAX_UPLOAD_BYTES)),
+		)
 		.route("/login", routing::get(auth::login::get))
 		.route("/login", routing::post(auth::login::post))
 		.route("/logout", routing::get(auth::logout::get))
@@ -103,13 +131,40 @@ async fn main() {
 		.route("/dump", routing::get(dump))
 		.nest_service("/dist", ServeDir::new("dist"))
 		.fallback(not_found)
-		.with_state(context);
+		.with_state(context.clone());
 
 	let addr = "0.0.0.0:8080";
 	let server = tokio::net::TcpListener::bind(addr).await.unwrap();
 
 	info!("Listening on {addr}");
-	axum::serve(server, app).await.unwrap();
+	axum::serve(server, app)
+		.with_graceful_shutdown(shutdown_signal())
+		.await
+		.unwrap();
+
+	info!("Shutting down, indexing queued pages");
+	context.search.close().await;
+}
+
+/// Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		signal::ctrl_c().await.unwrap();
+	};
+	#[cfg(unix)]
+	let terminate = async {
+		signal::unix::signal(signal::unix::SignalKind::terminate())
+			.unwrap()
+			.recv()
+			.await;
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		() = ctrl_c => {},
+		() = terminate => {},
+	}
 }
 
 async fn not_found() -> impl IntoResponse {
@@ -124,7 +179,11 @@ async fn dump(State(ctx): State<Arc<Context>>) -> impl IntoResponse {
 		.full_iterator_cf(&db.cf_handle(PAGE_CF).unwrap(), IteratorMode::Start);
 	for page in pages {
 		let (k, v) = page.unwrap();
-		info!("PAGE {:?} => {}", PageKey::dec(k), Page::dec(v).title());
+		info!(
+			"PAGE {:?} => {}",
+			PageKey::from_bytes(k),
+			Page::dec(v).title()
+		);
 	}
 
 	let history = db
@@ -153,6 +212,24 @@ async fn dump(State(ctx): State<Arc<Context>>) -> impl IntoResponse {
 		info!("USER {:?} => {:?}", UserKey::dec(k), User::dec(v));
 	}
 
+	let includes = db
+		.full_iterator_cf(&db.cf_handle(INCL_CF).unwrap(), IteratorMode::Start);
+	for include in includes {
+		let (k, v) = include.unwrap();
+		info!(
+			"INCL {:?} => {:?}",
+			String::from_utf8_lossy(&k),
+			IncludedBy::dec(v)
+		);
+	}
+
+	let blobs = db
+		.full_iterator_cf(&db.cf_handle(BLOB_CF).unwrap(), IteratorMode::Start);
+	for blob in blobs {
+		let (k, v) = blob.unwrap();
+		info!("BLOB {} => {} bytes", String::from_utf8_lossy(&k), v.len());
+	}
+
 	(StatusCode::OK, "OK")
 }
 
@@ -177,13 +254,18 @@ async fn seed_base(db: &TransactionDB) {
 			let title =
 				Path::file_stem(fname.as_ref()).unwrap().to_str().unwrap();
 			let content = fs::read_to_string(firent.path());
+			let namespace = namespace.to_str().unwrap();
+			let mut page =
+				Page::new(title, 0o644, Some("meta"), Some(content.unwrap()));
 
-			Page::put(
-				db,
-				namespace.to_str().unwrap(),
-				&Page::new(title, 0o644, Some("meta"), Some(content.unwrap())),
-			)
-			.await
+			// Rewriting unchanged pages would have them reindexed every start.
+			if Page::get(db, namespace, page.slug())
+				.await
+				.is_some_and(|stored| stored.content() == page.content())
+			{
+				continue;
+			}
+			Page::put(db, namespace, &mut page).await.unwrap();
 		}
 	}
 }
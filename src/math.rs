@@ -0,0 +1,25 @@
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+
+use crate::render::escape;
+
+/// MathML for `$...$` (inline) or `$$...$$` (display) LaTeX. Expressions that
+/// don't convert are shown as written, with the reason on hover.
+pub fn to_mathml(latex: &str, display: bool) -> String {
+	let style = if display {
+		DisplayStyle::Block
+	} else {
+		DisplayStyle::Inline
+	};
+	match latex_to_mathml(latex, style) {
+		Ok(mathml) => mathml,
+		Err(e) => {
+			let delimiter = if display { "$$" } else { "$" };
+			format!(
+				"<code class=\"math-error\" title=\"Can't render math: {}\">\
+				 {delimiter}{}{delimiter}</code>",
+				escape(&e.to_string()),
+				escape(latex)
+			)
+		}
+	}
+}
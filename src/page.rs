@@ -1,17 +1,49 @@
 use bincode::{Decode, Encode};
-use rocksdb::{DBIteratorWithThreadMode, IteratorMode, TransactionDB};
-use slug::slugify;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use rocksdb::{
+	DBIteratorWithThreadMode, IteratorMode, Transaction, TransactionDB,
+};
 use tantivy::schema::Facet;
+use time::OffsetDateTime;
 
 use crate::{
+	attachment::Attachment,
 	auth,
 	auth::{has_access, user::User},
 	encoding::{DbDecode, DbEncode},
-	PAGE_CF,
+	errors::WkError,
+	front_matter,
+	front_matter::PageMeta,
+	PAGE_CF, PEND_CF, SEQN_CF,
 };
 
-#[repr(transparent)]
-#[derive(Encode, Decode, Debug)]
+/// Characters dropped from titles when slugging, they would need escaping
+/// everywhere they're linked.
+const RESERVED: &[char] =
+	&['#', '<', '>', '[', ']', '|', '{', '}', '\\', '?', '%'];
+
+/// Characters escaped when a slug is used in a URL path. Slugs keep their `/`
+/// between subpages.
+const PATH: &AsciiSet = &CONTROLS
+	.add(b' ')
+	.add(b'"')
+	.add(b'#')
+	.add(b'<')
+	.add(b'>')
+	.add(b'?')
+	.add(b'`')
+	.add(b'{')
+	.add(b'}')
+	.add(b'%');
+
+/// Characters escaped in a single URL path segment, like a namespace.
+pub const PATH_SEGMENT: &AsciiSet = &PATH.add(b'/');
+
+/// Counter in `SEQN_CF` that orders page writes, see `Page::seq`.
+const SEQ_KEY: &str = "page";
+
+/// `namespace/slug`, stored as is so a namespace's pages sit together.
+#[derive(Debug, Clone)]
 pub struct PageKey(String);
 
 #[derive(Encode, Decode, Debug)]
@@ -21,11 +53,61 @@ pub struct Page {
 	pub mode: u16,
 	content: String,
 	owner: Option<String>,
+	/// Tags set on the edit page, see `tags` for all of them.
+	tags: Vec<String>,
+	meta: PageMeta,
+	attachments: Vec<Attachment>,
+	/// Taken from a counter each time the page is stored, so later writes have
+	/// higher numbers.
+	seq: u64,
+	/// When the page was last stored, in Unix seconds.
+	modified: i64,
+}
+
+/// `Page` as stored before tags, front matter, attachments and the search
+/// sequence, see `upgrade`.
+#[derive(Decode)]
+pub struct PageV0 {
+	title: String,
+	slug: String,
+	mode: u16,
+	content: String,
+	owner: Option<String>,
+}
+
+impl PageV0 {
+	pub fn title(&self) -> &str {
+		&self.title
+	}
+
+	/// Lowercase and hyphenated, e.g. "search-syntax".
+	pub fn slug(&self) -> &str {
+		&self.slug
+	}
+
+	/// The page in the current format, under `slug`.
+	pub fn into_page(self, slug: &str) -> Page {
+		Page {
+			meta: PageMeta::parse(&self.content).unwrap_or_default(),
+			title: self.title,
+			slug: slug.to_string(),
+			mode: self.mode,
+			content: self.content,
+			owner: self.owner,
+			tags: vec![],
+			attachments: vec![],
+			seq: 0,
+			modified: 0,
+		}
+	}
 }
 
 #[allow(dead_code)]
 impl Page {
 	pub const DEFAULT_MODE: u16 = 0o666;
+	/// Pages titled e.g. "Template:Runbook" are offered as starting points on
+	/// the create form.
+	pub const TEMPLATE_PREFIX: &'static str = "Template:";
 
 	// TODO: Better signature.
 	pub fn new(
@@ -34,15 +116,18 @@ impl Page {
 		owner: Option<&str>,
 		content: Option<String>,
 	) -> Self {
+		let content = content.unwrap_or_default();
 		Self {
 			mode,
 			title: title.to_string(),
 			owner: owner.map(str::to_string),
-			// TODO: I don't really like that this doesn't retain
-			//       capitalization. Write a slugger like Wikipedia, e.g.
-			//       https://en.wikipedia.org/wiki/Clean_URL
 			slug: slugify(title),
-			content: content.unwrap_or_default(),
+			meta: PageMeta::parse(&content).unwrap_or_default(),
+			content,
+			tags: vec![],
+			attachments: vec![],
+			seq: 0,
+			modified: 0,
 		}
 	}
 
@@ -50,44 +135,271 @@ impl Page {
 		&self.slug
 	}
 
+	/// The title from front matter, if any, otherwise the one the page was
+	/// created with.
 	pub fn title(&self) -> &str {
-		&self.title
+		self.meta.title.as_deref().unwrap_or(&self.title)
 	}
 
+	/// Content as written, including front matter.
 	pub fn content(&self) -> &str {
 		&self.content
 	}
 
-	pub fn set_content(&mut self, content: &str) {
-		self.content = content.to_string()
+	/// Content without front matter, for rendering.
+	pub fn body(&self) -> &str {
+		front_matter::split(&self.content).1
+	}
+
+	pub fn meta(&self) -> &PageMeta {
+		&self.meta
+	}
+
+	pub fn owner(&self) -> Option<&str> {
+		self.owner.as_deref()
+	}
+
+	pub fn seq(&self) -> u64 {
+		self.seq
+	}
+
+	pub fn modified(&self) -> i64 {
+		self.modified
+	}
+
+	/// Slug of the page this is a subpage of, e.g. "A/B" for "A/B/C".
+	pub fn parent_slug(&self) -> Option<&str> {
+		self.slug.rsplit_once('/').map(|(parent, _)| parent)
+	}
+
+	pub fn is_template(&self) -> bool {
+		self.slug.starts_with(Self::TEMPLATE_PREFIX)
+	}
+
+	/// Sets content, failing if its front matter doesn't parse.
+	pub fn set_content(
+		&mut self,
+		content: &str,
+	) -> Result<(), front_matter::Error> {
+		self.meta = PageMeta::parse(content)?;
+		self.content = content.to_string();
+		Ok(())
+	}
+
+	/// Tags from both the edit page and front matter.
+	pub fn tags(&self) -> Vec<&str> {
+		let mut tags = self
+			.tags
+			.iter()
+			.chain(&self.meta.tags)
+			.map(String::as_str)
+			.collect::<Vec<_>>();
+		tags.sort();
+		tags.dedup();
+		tags
+	}
+
+	pub fn edited_tags(&self) -> &[String] {
+		&self.tags
+	}
+
+	pub fn set_tags<'a>(&mut self, tags: impl IntoIterator<Item = &'a str>) {
+		let mut tags = tags
+			.into_iter()
+			.map(normalize_tag)
+			.filter(|tag| !tag.is_empty())
+			.collect::<Vec<_>>();
+		tags.sort();
+		tags.dedup();
+		self.tags = tags;
+	}
+
+	pub fn attachments(&self) -> &[Attachment] {
+		&self.attachments
+	}
+
+	pub fn attachment(&self, name: &str) -> Option<&Attachment> {
+		self.attachments.iter().find(|file| file.name == name)
+	}
+
+	/// Adds an attachment, replacing any with the same name.
+	pub fn attach(&mut self, attachment: Attachment) {
+		self.attachments.retain(|file| file.name != attachment.name);
+		self.attachments.push(attachment);
+		self.attachments.sort_by(|a, b| a.name.cmp(&b.name));
 	}
 
 	pub async fn get(db: &TransactionDB, ns: &str, slug: &str) -> Option<Self> {
 		let key = PageKey::new(ns, slug);
-		db.get_cf(&db.cf_handle(PAGE_CF).unwrap(), key.enc())
+		db.get_cf(&db.cf_handle(PAGE_CF).unwrap(), key.as_str())
 			.unwrap()
 			.map(Page::dec)
 	}
 
-	pub async fn put(db: &TransactionDB, ns: &str, page: &Self) {
+	/// Finds the page a non-canonical slug refers to, e.g. `users` or
+	/// `Foo bar` for `Users` and `Foo_bar`.
+	pub async fn find(
+		db: &TransactionDB,
+		ns: &str,
+		slug: &str,
+	) -> Option<Self> {
+		let canonical = slugify(slug);
+		if let Some(page) = Self::get(db, ns, &canonical).await {
+			return Some(page);
+		}
+
+		let folded = canonical.to_lowercase();
+		Self::list(db, ns)
+			.await
+			.find(|page| page.slug.to_lowercase() == folded)
+	}
+
+	pub async fn put(
+		db: &TransactionDB,
+		ns: &str,
+		page: &mut Self,
+	) -> Result<(), WkError> {
+		let tx = db.transaction();
+		Self::put_in(db, &tx, ns, page)?;
+		tx.commit()?;
+		Ok(())
+	}
+
+	/// Like `put`, as part of a larger transaction.
+	pub fn put_in(
+		db: &TransactionDB,
+		tx: &Transaction<'_, TransactionDB>,
+		ns: &str,
+		page: &mut Self,
+	) -> Result<(), WkError> {
 		let key = PageKey::new(ns, &page.slug);
-		db.put_cf(&db.cf_handle(PAGE_CF).unwrap(), key.enc(), page.enc())
-			.unwrap()
+		page.seq = Self::next_seq(db, tx)?;
+		page.modified = OffsetDateTime::now_utc().unix_timestamp();
+		tx.put_cf(&db.cf_handle(PAGE_CF).unwrap(), key.as_str(), page.enc())?;
+		Self::set_unindexed(db, tx, &key, page.seq)
+	}
+
+	/// Stores a new page, refusing to clobber an existing page with the same
+	/// slug, ignoring case.
+	pub async fn create(
+		db: &TransactionDB,
+		ns: &str,
+		page: &mut Self,
+	) -> Result<(), WkError> {
+		if Self::find(db, ns, &page.slug).await.is_some() {
+			return Err(WkError::Exists);
+		}
+
+		let cf = db.cf_handle(PAGE_CF).unwrap();
+		let key = PageKey::new(ns, &page.slug);
+		let tx = db.transaction();
+		// Someone may have created the same slug since the lookup above.
+		if tx.get_for_update_cf(&cf, key.as_str(), true)?.is_some() {
+			return Err(WkError::Exists);
+		}
+		page.seq = Self::next_seq(db, &tx)?;
+		page.modified = OffsetDateTime::now_utc().unix_timestamp();
+		tx.put_cf(&cf, key.as_str(), page.enc())?;
+		Self::set_unindexed(db, &tx, &key, page.seq)?;
+		tx.commit()?;
+
+		Ok(())
+	}
+
+	/// Takes the next number for `seq`, as part of the transaction storing
+	/// the page.
+	fn next_seq(
+		db: &TransactionDB,
+		tx: &Transaction<'_, TransactionDB>,
+	) -> Result<u64, WkError> {
+		let cf = db.cf_handle(SEQN_CF).unwrap();
+		let seq = tx
+			.get_for_update_cf(&cf, SEQ_KEY, true)?
+			.map_or(0, u64::dec)
+			+ 1;
+		tx.put_cf(&cf, SEQ_KEY, seq.enc())?;
+		Ok(seq)
+	}
+
+	/// Marks a page as written since the search index last committed it, as
+	/// part of the transaction storing the page.
+	fn set_unindexed(
+		db: &TransactionDB,
+		tx: &Transaction<'_, TransactionDB>,
+		key: &PageKey,
+		seq: u64,
+	) -> Result<(), WkError> {
+		tx.put_cf(&db.cf_handle(PEND_CF).unwrap(), key.as_str(), seq.enc())?;
+		Ok(())
+	}
+
+	/// Pages written since the search index last committed them.
+	pub fn unindexed(
+		db: &TransactionDB,
+	) -> Result<Vec<(PageKey, Self)>, WkError> {
+		let pages = db.cf_handle(PAGE_CF).unwrap();
+		let mut unindexed = vec![];
+		for entry in db.full_iterator_cf(
+			&db.cf_handle(PEND_CF).unwrap(),
+			IteratorMode::Start,
+		) {
+			let (key, _) = entry?;
+			if let Some(page) = db.get_cf(&pages, &key)? {
+				unindexed.push((PageKey::from_bytes(key), Page::dec(page)));
+			}
+		}
+		Ok(unindexed)
+	}
+
+	/// Marks a page as indexed as of `seq`, once the index has committed it,
+	/// unless it's been written again since.
+	pub fn set_indexed(
+		db: &TransactionDB,
+		key: &PageKey,
+		seq: u64,
+	) -> Result<(), WkError> {
+		let cf = db.cf_handle(PEND_CF).unwrap();
+		let tx = db.transaction();
+		let written =
+			tx.get_for_update_cf(&cf, key.as_str(), true)?.map(u64::dec);
+		if written.is_some_and(|written| written <= seq) {
+			tx.delete_cf(&cf, key.as_str())?;
+		}
+		tx.commit()?;
+		Ok(())
 	}
 
 	pub async fn list<'a>(
 		db: &'a TransactionDB,
 		ns: &str,
-	) -> impl Iterator + 'a {
-		db.prefix_iterator_cf(
-			&db.cf_handle(PAGE_CF).unwrap(),
-			format!("{ns}/").enc(),
-		)
+	) -> impl Iterator<Item = Self> + 'a {
+		let prefix = format!("{ns}/");
+		// Without a prefix extractor the iterator runs past the namespace, so
+		// stop at its last key.
+		// TODO: Handle the errors.
+		db.prefix_iterator_cf(&db.cf_handle(PAGE_CF).unwrap(), prefix.clone())
+			.flatten()
+			.take_while(move |(k, _)| k.starts_with(prefix.as_bytes()))
+			.map(|(_, v)| Page::dec(v))
+	}
+
+	/// Direct subpages of a page.
+	pub async fn children<'a>(
+		db: &'a TransactionDB,
+		ns: &str,
+		slug: &str,
+	) -> impl Iterator<Item = Self> + 'a {
+		let prefix = format!("{slug}/");
+		Self::list(db, ns).await.filter(move |page| {
+			page.slug
+				.strip_prefix(&prefix)
+				.is_some_and(|rest| !rest.contains('/'))
+		})
 	}
 
 	pub async fn list_all(
 		db: &TransactionDB,
-	) -> DBIteratorWithThreadMode<TransactionDB> {
+	) -> DBIteratorWithThreadMode<'_, TransactionDB> {
 		db.full_iterator_cf(
 			&db.cf_handle(PAGE_CF).unwrap(),
 			IteratorMode::Start,
@@ -127,7 +439,117 @@ impl PageKey {
 		Self(format!("{ns}/{slug}"))
 	}
 
+	pub fn from_bytes<B>(bytes: B) -> Self
+	where
+		B: AsRef<[u8]>,
+	{
+		// TODO: Decoding errors.
+		Self(String::from_utf8(bytes.as_ref().to_vec()).unwrap())
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn namespace(&self) -> &str {
+		self.0.split_once('/').map_or(&self.0, |(ns, _)| ns)
+	}
+
 	pub fn as_facet(&self) -> Facet {
 		Facet::from_text(&format!("/{}", self.0)).unwrap()
 	}
 }
+
+/// Wikipedia style slugs, see https://en.wikipedia.org/wiki/Clean_URL.
+///
+/// Runs of whitespace and underscores become a single `_` and the first letter
+/// is capitalized. Everything else, case and Unicode included, is kept as
+/// written, e.g. "the  Quick brown_fox" => "The_Quick_brown_fox".
+///
+/// `/` separates subpages, each of which is slugged on its own, e.g.
+/// "runbooks/ database" => "Runbooks/Database".
+pub fn slugify(title: &str) -> String {
+	title
+		.split('/')
+		.map(slugify_segment)
+		.filter(|segment| !segment.is_empty())
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+fn slugify_segment(title: &str) -> String {
+	let cleaned = title
+		.chars()
+		.filter(|c| !c.is_control() && !RESERVED.contains(c))
+		.collect::<String>();
+	let words = cleaned
+		.split(|c: char| c.is_whitespace() || c == '_')
+		.filter(|word| !word.is_empty())
+		.collect::<Vec<_>>()
+		.join("_");
+
+	let mut chars = words.chars();
+	match chars.next() {
+		Some(first) => first.to_uppercase().chain(chars).collect(),
+		None => String::new(),
+	}
+}
+
+/// Tags are lowercase words joined by `-`, e.g. "Incident Reports" =>
+/// "incident-reports". Like slugs they go in URLs, `/tags/...`, so characters
+/// reserved in slugs are dropped, e.g. "#C#" => "c".
+pub fn normalize_tag(tag: &str) -> String {
+	tag.split(|c: char| c.is_whitespace() || c == '/' || c == ',')
+		.map(|word| {
+			word.chars()
+				.filter(|c| !RESERVED.contains(c))
+				.collect::<String>()
+				.to_lowercase()
+		})
+		.filter(|word| !word.is_empty())
+		.collect::<Vec<_>>()
+		.join("-")
+}
+
+/// Absolute, percent-encoded URL of a page.
+pub fn url(ns: &str, slug: &str) -> String {
+	format!(
+		"/{}/{}",
+		utf8_percent_encode(ns, PATH_SEGMENT),
+		utf8_percent_encode(slug, PATH)
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn slugify_joins_words() {
+		assert_eq!(slugify("the  Quick brown_fox"), "The_Quick_brown_fox");
+		assert_eq!(slugify("  disk  checks "), "Disk_checks");
+		assert_eq!(slugify("étude"), "Étude");
+	}
+
+	#[test]
+	fn slugify_drops_reserved_characters() {
+		assert_eq!(slugify("C# <notes> [draft]?"), "C_notes_draft");
+		assert_eq!(slugify("100%"), "100");
+		// Colons are kept, templates are titled "Template:...".
+		assert_eq!(slugify("Template:runbook"), "Template:runbook");
+	}
+
+	#[test]
+	fn slugify_slugs_subpages_on_their_own() {
+		assert_eq!(slugify("runbooks/ database"), "Runbooks/Database");
+		assert_eq!(slugify("/a//b/"), "A/B");
+		assert_eq!(slugify("/"), "");
+	}
+
+	#[test]
+	fn normalize_tag_drops_reserved_characters() {
+		assert_eq!(normalize_tag("Incident Reports"), "incident-reports");
+		assert_eq!(normalize_tag("#C# 100% ?"), "c-100");
+		assert_eq!(normalize_tag("a/b,c"), "a-b-c");
+	}
+}
@@ -0,0 +1,395 @@
+use std::{ops::Range, sync::LazyLock};
+
+use bincode::{Decode, Encode};
+use comrak::{
+	format_html_with_plugins, html,
+	nodes::{AstNode, NodeHtmlBlock, NodeValue},
+	parse_document, Anchorizer, Arena, Options, Plugins,
+};
+
+use crate::{diagram, errors::WkError, highlight::Highlighter, math};
+
+/// Anywhere in a page outside code, turns off its table of contents.
+const NOTOC: &str = "__NOTOC__";
+
+/// Pages with more headings than this get a table of contents.
+const TOC_THRESHOLD: usize = 3;
+
+/// MathML produced for `$...$` math, see `math`.
+#[rustfmt::skip]
+const MATHML_TAGS: &[&str] = &[
+	"math", "mi", "mn", "mo", "ms", "mtext", "mspace", "mrow", "mfrac",
+	"msqrt", "mroot", "mstyle", "merror", "mpadded", "mphantom", "menclose",
+	"msub", "msup", "msubsup", "munder", "mover", "munderover", "mtable",
+	"mtr", "mtd", "semantics", "annotation",
+];
+#[rustfmt::skip]
+const MATHML_ATTRIBUTES: &[&str] = &[
+	"xmlns", "display", "mathvariant", "displaystyle", "scriptlevel",
+	"stretchy", "fence", "separator", "lspace", "rspace", "accent",
+	"accentunder", "movablelimits", "largeop", "symmetric", "minsize",
+	"maxsize", "linethickness", "notation", "columnalign", "rowalign",
+	"columnspan", "rowspan", "width", "height", "depth", "encoding",
+];
+
+/// SVG drawn for ```` ```dot ```` blocks, see `diagram`.
+#[rustfmt::skip]
+const SVG_TAGS: &[&str] = &[
+	"svg", "defs", "marker", "clipPath", "g", "path", "polygon", "rect",
+	"ellipse", "line", "text", "textPath", "tspan",
+];
+#[rustfmt::skip]
+const SVG_ATTRIBUTES: &[&str] = &[
+	"xmlns", "width", "height", "viewBox", "x", "y", "x1", "y1", "x2", "y2",
+	"cx", "cy", "rx", "ry", "d", "points", "fill", "stroke", "stroke-width",
+	"stroke-dasharray", "font-size", "text-anchor", "dominant-baseline", "dy",
+	"href", "startOffset", "clip-path", "marker-start", "marker-end",
+	"markerWidth", "markerHeight", "refX", "refY", "orient",
+];
+
+/// Put in front of every id in a page, so pages can't clobber the ids of the
+/// page around them, e.g. `#toc`. Links within the page are changed to match.
+pub const ID_PREFIX: &str = "user-content-";
+
+/// Classes the markup we generate uses, anything else is dropped. Highlighted
+/// code also keeps its `hl-` classes, see `highlight`.
+#[rustfmt::skip]
+const CLASSES: &[&str] = &[
+	"anchor", "highlight", "footnotes", "footnote-ref", "footnote-backref",
+	"task-list-item", "math-error", "diagram", "diagram-error",
+];
+
+/// Every rendered page goes through this allow-list, whatever its namespace
+/// trusts. On top of ammonia's defaults: heading anchors, footnotes, task list
+/// checkboxes, math and diagrams.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+	let mut sanitizer = ammonia::Builder::default();
+	sanitizer
+		.add_tags(["section", "input"])
+		.add_tags(MATHML_TAGS)
+		.add_tags(SVG_TAGS)
+		.add_generic_attributes(["id", "class"])
+		.id_prefix(Some(ID_PREFIX))
+		.add_tag_attributes("input", ["type", "checked"])
+		.set_tag_attribute_value("input", "disabled", "")
+		.attribute_filter(|element, attribute, value| {
+			match (element, attribute) {
+				("input", "type") if value != "checkbox" => None,
+				(_, "class") => {
+					let classes = value
+						.split_whitespace()
+						.filter(|class| {
+							class.starts_with("hl-") || CLASSES.contains(class)
+						})
+						.collect::<Vec<_>>();
+					(!classes.is_empty()).then(|| classes.join(" ").into())
+				}
+				(_, "href") => match value.strip_prefix('#') {
+					Some(id) if !id.starts_with(ID_PREFIX) => {
+						Some(format!("#{ID_PREFIX}{id}").into())
+					}
+					_ => Some(value.into()),
+				},
+				// Diagrams point at their own markers and clip paths.
+				(_, "clip-path" | "marker-start" | "marker-end") => Some(
+					value.replace("url(#", &format!("url(#{ID_PREFIX}")).into(),
+				),
+				_ => Some(value.into()),
+			}
+		});
+	for tag in MATHML_TAGS {
+		sanitizer.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+	}
+	for tag in SVG_TAGS {
+		sanitizer.add_tag_attributes(tag, SVG_ATTRIBUTES);
+	}
+	sanitizer
+});
+
+/// Markdown extensions a namespace renders with. GitHub's are on by default.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct MarkdownOptions {
+	pub table: bool,
+	pub strikethrough: bool,
+	pub tasklist: bool,
+	pub autolink: bool,
+	pub footnotes: bool,
+	/// Curly quotes, en and em dashes.
+	pub smart: bool,
+	/// Single newlines are line breaks.
+	pub hardbreaks: bool,
+	/// `$...$` and `$$...$$` LaTeX.
+	pub math: bool,
+	/// HTML in pages is kept, otherwise it's shown as text. It's sanitized
+	/// either way.
+	pub raw_html: bool,
+}
+
+impl Default for MarkdownOptions {
+	fn default() -> Self {
+		Self {
+			table: true,
+			strikethrough: true,
+			tasklist: true,
+			autolink: true,
+			footnotes: true,
+			smart: false,
+			hardbreaks: false,
+			math: true,
+			raw_html: false,
+		}
+	}
+}
+
+impl MarkdownOptions {
+	/// Turns on the listed extensions, e.g. "table, footnotes", and off the
+	/// rest.
+	pub fn set_extensions(&mut self, list: &str) -> Result<(), WkError> {
+		let mut options = Self {
+			table: false,
+			strikethrough: false,
+			tasklist: false,
+			autolink: false,
+			footnotes: false,
+			smart: false,
+			hardbreaks: false,
+			math: false,
+			raw_html: self.raw_html,
+		};
+		for name in list.split([',', ' ']).filter(|name| !name.is_empty()) {
+			match name {
+				"table" => options.table = true,
+				"strikethrough" => options.strikethrough = true,
+				"tasklist" => options.tasklist = true,
+				"autolink" => options.autolink = true,
+				"footnotes" => options.footnotes = true,
+				"smart" => options.smart = true,
+				"hardbreaks" => options.hardbreaks = true,
+				"math" => options.math = true,
+				_ => return Err(WkError::InvalidArgument),
+			}
+		}
+		*self = options;
+		Ok(())
+	}
+
+	fn configure(&self, options: &mut Options) {
+		options.extension.table = self.table;
+		options.extension.strikethrough = self.strikethrough;
+		options.extension.tasklist = self.tasklist;
+		options.extension.autolink = self.autolink;
+		options.extension.footnotes = self.footnotes;
+		options.parse.smart = self.smart;
+		options.render.hardbreaks = self.hardbreaks;
+		options.extension.math_dollars = self.math;
+		// Raw HTML is escaped in the tree instead, see `render`, so what we put
+		// there ourselves comes through.
+		options.render.unsafe_ = true;
+	}
+}
+
+pub struct Rendered {
+	pub html: String,
+	/// Empty when the page is short or opted out.
+	pub toc: Vec<TocEntry>,
+}
+
+pub struct TocEntry {
+	pub title: String,
+	/// Heading id, e.g. "user-content-getting-started".
+	pub anchor: String,
+	/// Nesting below the page's top heading level, starting at 0.
+	pub depth: u8,
+}
+
+/// Stands in for the `index`th of `included` in markdown passed to `render`.
+pub fn placeholder(index: usize) -> String {
+	format!("\u{E000}{index}\u{E000}")
+}
+
+/// Renders a page body to HTML. Headings get anchors, see `TocEntry::anchor`.
+/// This is the only place markdown becomes HTML, pages and previews alike, and
+/// the HTML is always sanitized.
+///
+/// `included` is HTML already rendered and sanitized, put in place of its
+/// `placeholder` afterwards, so it isn't rendered again with these options.
+pub fn render(
+	markdown: &str,
+	markdown_options: &MarkdownOptions,
+	included: &[String],
+) -> Rendered {
+	let (markdown, notoc) = strip_notoc(markdown);
+
+	let mut options = Options::default();
+	markdown_options.configure(&mut options);
+	options.extension.tagfilter = true;
+	options.extension.header_ids = Some(String::new());
+
+	let arena = Arena::new();
+	let root = parse_document(&arena, &markdown, &options);
+
+	for node in root.descendants() {
+		let mut data = node.data.borrow_mut();
+		let replacement = match data.value {
+			NodeValue::Math(ref math) => Some(NodeValue::HtmlInline(
+				math::to_mathml(&math.literal, math.display_math),
+			)),
+			NodeValue::CodeBlock(ref code)
+				if code.fenced
+					&& matches!(code.info.trim(), "dot" | "graphviz") =>
+			{
+				Some(NodeValue::HtmlBlock(NodeHtmlBlock {
+					block_type: 6,
+					literal: diagram::to_svg(&code.literal),
+				}))
+			}
+			NodeValue::HtmlInline(ref html) if !markdown_options.raw_html => {
+				Some(NodeValue::Text(html.clone()))
+			}
+			NodeValue::HtmlBlock(ref html) if !markdown_options.raw_html => {
+				Some(NodeValue::Text(html.literal.clone()))
+			}
+			_ => None,
+		};
+		if let Some(value) = replacement {
+			data.value = value;
+		}
+	}
+
+	// comrak gives headings their ids in document order with the same
+	// anchorizer, so these match the rendered ones.
+	let mut anchorizer = Anchorizer::new();
+	let mut headings = vec![];
+	for node in root.descendants() {
+		if let NodeValue::Heading(ref heading) = node.data.borrow().value {
+			let title = text(node);
+			let anchor =
+				format!("{ID_PREFIX}{}", anchorizer.anchorize(title.clone()));
+			headings.push((heading.level, title, anchor));
+		}
+	}
+
+	let mut plugins = Plugins::default();
+	plugins.render.codefence_syntax_highlighter = Some(&Highlighter);
+	let mut html = vec![];
+	// Writing to a Vec can't fail.
+	format_html_with_plugins(root, &options, &mut html, &plugins).unwrap();
+
+	let top = headings.iter().map(|(level, ..)| *level).min().unwrap_or(1);
+	let toc = if notoc || headings.len() <= TOC_THRESHOLD {
+		vec![]
+	} else {
+		headings
+			.into_iter()
+			.map(|(level, title, anchor)| TocEntry {
+				title,
+				anchor,
+				depth: level - top,
+			})
+			.collect()
+	};
+
+	let mut html = SANITIZER.clean(&String::from_utf8_lossy(&html)).to_string();
+	for (index, part) in included.iter().enumerate() {
+		let placeholder = placeholder(index);
+		// On a line of its own, rather than in a paragraph.
+		html = html
+			.replace(
+				&format!("<p>{placeholder}</p>"),
+				&format!("<div>{part}</div>"),
+			)
+			.replace(&placeholder, part);
+	}
+
+	Rendered { html, toc }
+}
+
+/// Escapes text for HTML, e.g. error messages shown in a page.
+pub fn escape(text: &str) -> String {
+	let mut escaped = vec![];
+	// Writing to a Vec can't fail.
+	html::escape(&mut escaped, text.as_bytes()).unwrap();
+	String::from_utf8(escaped).unwrap()
+}
+
+/// Plain text of a node, e.g. a heading's title without its formatting.
+fn text<'a>(node: &'a AstNode<'a>) -> String {
+	let mut text = String::new();
+	for child in node.descendants() {
+		match child.data.borrow().value {
+			NodeValue::Text(ref literal) => text.push_str(literal),
+			NodeValue::Code(ref code) => text.push_str(&code.literal),
+			NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+			_ => {}
+		}
+	}
+	text
+}
+
+/// Removes `__NOTOC__` from some markdown, saying whether it was there. It
+/// would otherwise render as bold "NOTOC".
+fn strip_notoc(markdown: &str) -> (String, bool) {
+	let code = code_ranges(markdown);
+	let mut stripped = String::with_capacity(markdown.len());
+	let mut last = 0;
+	for (start, _) in markdown.match_indices(NOTOC) {
+		if code.iter().any(|range| range.contains(&start)) {
+			continue;
+		}
+		stripped.push_str(&markdown[last..start]);
+		last = start + NOTOC.len();
+	}
+	stripped.push_str(&markdown[last..]);
+
+	let found = last != 0;
+	(stripped, found)
+}
+
+/// Where fenced code blocks and inline code spans are in some markdown.
+pub fn code_ranges(text: &str) -> Vec<Range<usize>> {
+	let mut ranges = vec![];
+	// Start and marker of the fenced block we're in.
+	let mut fence: Option<(usize, &str)> = None;
+	let mut offset = 0;
+	for line in text.split_inclusive('\n') {
+		let trimmed = line.trim_start();
+		match fence {
+			Some((start, marker)) => {
+				// Closed by a bare fence at least as long as the opening one.
+				let fence_char = &marker[..1];
+				if trimmed.starts_with(marker)
+					&& trimmed.trim_start_matches(fence_char).trim().is_empty()
+				{
+					ranges.push(start..offset + line.len());
+					fence = None;
+				}
+			}
+			None if trimmed.starts_with("```")
+				|| trimmed.starts_with("~~~") =>
+			{
+				let fence_char = &trimmed[..1];
+				let run = trimmed.len()
+					- trimmed.trim_start_matches(fence_char).len();
+				fence = Some((offset, &trimmed[..run]));
+			}
+			None => {
+				let mut pos = 0;
+				while let Some(open) = line[pos..].find('`').map(|i| pos + i) {
+					let run = line[open..].len()
+						- line[open..].trim_start_matches('`').len();
+					let marker = &line[open..open + run];
+					let Some(close) = line[open + run..].find(marker) else {
+						break;
+					};
+					pos = open + run + close + run;
+					ranges.push(offset + open..offset + pos);
+				}
+			}
+		}
+		offset += line.len();
+	}
+	if let Some((start, _)) = fence {
+		ranges.push(start..text.len());
+	}
+	ranges
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use axum::{
+	extract::{FromRequest, Multipart, Path, Query, Request, State},
+	http::Uri,
+	response::{IntoResponse, Response},
+	Form,
+};
+use axum_extra::{headers, TypedHeader};
+
+use crate::{attachment, edit, history, not_found, view, Context};
+
+/// What to do with a page, taken from the end of its path. Slug segments
+/// always start with a capital (see `page::slugify`), so these never collide
+/// with a subpage, e.g. `/ns/Parent/Child/edit`.
+#[derive(Debug, PartialEq)]
+enum Action {
+	View,
+	Edit,
+	History,
+	/// Renders posted content for the edit form.
+	Preview,
+	/// Uploads go to `/ns/Page/files`.
+	Files,
+	/// `/ns/Page/files/name`.
+	File(String),
+}
+
+/// Attachments are matched first, so they can be named like an action, e.g.
+/// `/ns/Page/files/edit`.
+fn split(path: &str) -> (String, Action) {
+	let path = path.trim_end_matches('/');
+	let Some((rest, last)) = path.rsplit_once('/') else {
+		return (path.to_string(), Action::View);
+	};
+	if let Some((slug, "files")) = rest.rsplit_once('/') {
+		return (slug.to_string(), Action::File(last.to_string()));
+	}
+	match last {
+		"edit" => (rest.to_string(), Action::Edit),
+		"history" => (rest.to_string(), Action::History),
+		"preview" => (rest.to_string(), Action::Preview),
+		"files" => (rest.to_string(), Action::Files),
+		_ => (path.to_string(), Action::View),
+	}
+}
+
+pub async fn get(
+	Path((ns, path)): Path<(String, String)>,
+	uri: Uri,
+	cookies: TypedHeader<headers::Cookie>,
+	state: State<Arc<Context>>,
+) -> Response {
+	let (slug, action) = split(&path);
+	let path = Path((ns, slug));
+	match action {
+		Action::View => view::get(path, cookies, state).await.into_response(),
+		Action::Edit => edit::get(path, cookies, state).await.into_response(),
+		Action::History => {
+			history::get(path, cookies, state).await.into_response()
+		}
+		Action::File(name) => {
+			let Path((ns, slug)) = path;
+			let params = match Query::try_from_uri(&uri) {
+				Ok(params) => params,
+				Err(rejection) => return rejection.into_response(),
+			};
+			attachment::download(Path((ns, slug, name)), params, cookies, state)
+				.await
+		}
+		Action::Preview | Action::Files => not_found().await.into_response(),
+	}
+}
+
+pub async fn post(
+	Path((ns, path)): Path<(String, String)>,
+	state: State<Arc<Context>>,
+	cookies: TypedHeader<headers::Cookie>,
+	request: Request,
+) -> Response {
+	match split(&path) {
+		(slug, Action::Edit) => {
+			let form = match Form::from_request(request, &state.0).await {
+				Ok(form) => form,
+				Err(rejection) => return rejection.into_response(),
+			};
+			edit::post(Path((ns, slug)), state, cookies, form)
+				.await
+				.into_response()
+		}
+		(slug, Action::Preview) => {
+			let form = match Form::from_request(request, &state.0).await {
+				Ok(form) => form,
+				Err(rejection) => return rejection.into_response(),
+			};
+			edit::preview(Path((ns, slug)), state, cookies, form)
+				.await
+				.into_response()
+		}
+		(slug, Action::Files) => {
+			let multipart =
+				match Multipart::from_request(request, &state.0).await {
+					Ok(multipart) => multipart,
+					Err(rejection) => return rejection.into_response(),
+				};
+			attachment::upload(Path((ns, slug)), state, cookies, multipart)
+				.await
+		}
+		_ => not_found().await.into_response(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn split_takes_the_action_off() {
+		assert_eq!(split("Page"), ("Page".to_string(), Action::View));
+		assert_eq!(split("Page/edit"), ("Page".to_string(), Action::Edit));
+		assert_eq!(split("Page/edit/"), ("Page".to_string(), Action::Edit));
+		assert_eq!(
+			split("Parent/Child/history"),
+			("Parent/Child".to_string(), Action::History)
+		);
+		assert_eq!(split("Page/files"), ("Page".to_string(), Action::Files));
+		assert_eq!(
+			split("Parent/Child"),
+			("Parent/Child".to_string(), Action::View)
+		);
+	}
+
+	#[test]
+	fn split_finds_attachments() {
+		assert_eq!(
+			split("Parent/Child/files/report.pdf"),
+			(
+				"Parent/Child".to_string(),
+				Action::File("report.pdf".to_string())
+			)
+		);
+	}
+
+	#[test]
+	fn split_finds_attachments_named_like_actions() {
+		for name in ["edit", "history", "preview", "files"] {
+			assert_eq!(
+				split(&format!("Page/files/{name}")),
+				("Page".to_string(), Action::File(name.to_string()))
+			);
+		}
+	}
+}
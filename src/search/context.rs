@@ -1,141 +1,1061 @@
+use std::{
+	cmp::Reverse,
+	collections::HashMap,
+	fs,
+	ops::Bound,
+	sync::{atomic::Ordering, Arc, Mutex},
+};
+
+use rocksdb::TransactionDB;
+use serde::{Deserialize, Serialize};
 use tantivy::{
-	collector::TopDocs,
+	collector::{Count, TopDocs},
+	directory::MmapDirectory,
 	doc,
-	query::{BooleanQuery, Occur, QueryClone, QueryParser, TermSetQuery},
-	schema::{document::Value, Facet, Field, Schema, STORED, TEXT},
-	Index, IndexWriter, TantivyDocument, Term,
+	query::{
+		AllQuery, BooleanQuery, BoostQuery, ConstScoreQuery, FuzzyTermQuery,
+		MoreLikeThisQuery, Occur, Query, QueryClone, QueryParser, RangeQuery,
+		TermQuery, TermSetQuery,
+	},
+	schema::{
+		document::Value, Facet, Field, IndexRecordOption, Schema,
+		TextFieldIndexing, TextOptions, FAST, INDEXED, STORED, STRING, TEXT,
+	},
+	snippet::SnippetGenerator,
+	tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer},
+	DateTime, DocAddress, Index, IndexReader, Order, ReloadPolicy, Searcher,
+	SegmentReader, TantivyDocument, TantivyError, Term,
 };
+use tokio::sync::{mpsc, mpsc::error::TrySendError, oneshot};
+use tracing::{error, warn};
 
+use super::{
+	indexer::{Indexer, Metrics, Update},
+	language,
+};
 use crate::{
-	encoding::DbDecode,
+	auth::{namespace::Namespace, user::User},
+	front_matter, page,
 	page::{Page, PageKey},
 };
 
 pub struct SearchContext {
-	index: Index,
-	index_writer: IndexWriter,
+	/// Reloads shortly after each of the indexer's commits.
+	reader: IndexReader,
 	query_parser: QueryParser,
-	f_path: Field,
-	f_title: Field,
-	f_slug: Field,
-	f_content: Field,
+	fields: Fields,
+	/// To the indexer, see `Indexer::run`.
+	updates: mpsc::Sender<Update>,
+	metrics: Arc<Metrics>,
+	/// Pages found by `related`, starting over when it's full.
+	related: Mutex<HashMap<RelatedKey, Vec<Suggestion>>>,
+}
+
+/// What pages `related` finds depends on, so views of a page don't search
+/// again until it or the index changes.
+#[derive(PartialEq, Eq, Hash)]
+struct RelatedKey {
+	page: String,
+	seq: u64,
+	/// Of the searcher, see `SearcherGeneration`.
+	generation: u64,
+	user: Option<String>,
+	namespaces: Vec<String>,
+}
+
+/// Fields of the index, for searches and the indexer alike.
+#[derive(Clone)]
+pub(super) struct Fields {
+	pub f_key: Field,
+	pub f_path: Field,
+	pub f_title: Field,
+	pub f_title_sort: Field,
+	pub f_title_prefix: Field,
+	pub f_modified: Field,
+	pub f_slug: Field,
+	pub f_content: Field,
+	pub f_tags: Field,
+	pub f_author: Field,
+	pub f_read: Field,
+	pub f_summary: Field,
+	pub f_contact: Field,
+	pub f_review_by: Field,
+	pub f_meta: Field,
+	/// Language code => its title and content fields, analyzed in that
+	/// language, see `language`.
+	pub f_stemmed: Vec<(&'static str, Field, Field)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+	#[default]
+	Relevance,
+	/// A to Z.
+	Title,
+	/// Most recently changed first.
+	Modified,
+}
+
+impl Sort {
+	pub const ALL: [Self; 3] = [Self::Relevance, Self::Title, Self::Modified];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Relevance => "relevance",
+			Self::Title => "title",
+			Self::Modified => "modified",
+		}
+	}
+}
+
+/// Which of the sorted results to return.
+pub struct Paging {
+	pub offset: usize,
+	pub limit: usize,
+}
+
+pub struct Hits {
+	/// Matching pages, of which `results` is one page.
+	pub(super) total: usize,
+	pub(super) results: Vec<QueryResult>,
+	/// Why parts of the query were left out, for the user.
+	pub(super) errors: Vec<String>,
+	/// The query with its misspelled words corrected, when nothing matched.
+	pub(super) did_you_mean: Option<String>,
+}
+
+/// A page to offer, by title.
+#[derive(Serialize, Clone)]
+pub struct Suggestion {
+	pub title: String,
+	pub url: String,
 }
 
 pub struct QueryResult {
 	pub(super) namespace: String,
 	pub(super) slug: String,
 	pub(super) title: String,
+	/// Content around the matched terms, as HTML with the terms in `<b>`.
+	/// Empty when the query has no terms, e.g. only `tags:`.
+	pub(super) snippet: String,
+}
+
+impl QueryResult {
+	pub fn url(&self) -> String {
+		page::url(&self.namespace, &self.slug)
+	}
 }
 
 impl SearchContext {
-	const INDEX_SIZE_BYTES: usize = 0xF00_000; // 15MB is the minimum.
+	const SNIPPET_CHARS: usize = 200;
+	const TITLE_SORT: &'static str = "title_sort";
+	const MODIFIED: &'static str = "modified";
+	/// Indexes "r", "ru", "run"... for "Runbook".
+	const PREFIX_TOKENIZER: &'static str = "edge_ngram";
+	const MAX_PREFIX: usize = 20;
+	const SUGGESTIONS: usize = 8;
+	/// Fuzzy matches count for less than exact ones.
+	const FUZZY_BOOST: f32 = 0.5;
+	/// Nearest words checked for a correction, each is a search.
+	const MAX_CANDIDATES: usize = 32;
+	const RELATED: usize = 5;
+	/// Words in fewer pages than this, the page itself included, don't make
+	/// others related.
+	const RELATED_MIN_PAGES: u64 = 2;
+	/// A shared tag counts for more than a shared word.
+	const RELATED_TAG_BOOST: f32 = 2.0;
+	const RELATED_CACHE: usize = 1024;
+	pub(super) const READ_OTHERS: &'static str = "others";
+	pub(super) const READ_NAMESPACE: &'static str = "namespace";
 
-	pub async fn new(db: &rocksdb::TransactionDB) -> Self {
+	/// Opens the index at `path`, indexing the pages written since it was last
+	/// committed, or all of them if it's new.
+	pub async fn new(db: Arc<TransactionDB>, path: &str) -> Self {
 		let mut schema_builder = Schema::builder();
+		// Exact "namespace/slug", unlike `path` which also matches subpages.
+		let f_key = schema_builder.add_text_field("key", STRING);
 		let f_path = schema_builder.add_facet_field("path", STORED);
 		let f_slug = schema_builder.add_text_field("slug", TEXT | STORED);
 		let f_title = schema_builder.add_text_field("title", TEXT | STORED);
-		let f_content = schema_builder.add_text_field("content", TEXT);
+		// Starts of the words in the title, see `suggest`.
+		let f_title_prefix = schema_builder.add_text_field(
+			"title_prefix",
+			TextOptions::default().set_indexing_options(
+				TextFieldIndexing::default()
+					.set_tokenizer(Self::PREFIX_TOKENIZER)
+					.set_index_option(IndexRecordOption::Basic),
+			),
+		);
+		// Lowercase title to sort by.
+		let f_title_sort =
+			schema_builder.add_text_field(Self::TITLE_SORT, FAST);
+		let f_modified = schema_builder
+			.add_date_field(Self::MODIFIED, INDEXED | STORED | FAST);
+		// Stored for result snippets.
+		let f_content = schema_builder.add_text_field("content", TEXT | STORED);
+		let f_tags = schema_builder.add_facet_field("tags", STORED);
+		// The page's owner, who can always read it.
+		let f_author = schema_builder.add_text_field("author", STRING);
+		// Who else can read the page, see `access_filter`.
+		let f_read = schema_builder.add_text_field("read", STRING);
+		// Front matter.
+		let f_summary = schema_builder.add_text_field("summary", TEXT);
+		let f_contact = schema_builder.add_text_field("contact", TEXT);
+		let f_review_by =
+			schema_builder.add_date_field("review_by", INDEXED | STORED);
+		let f_meta = schema_builder.add_text_field("meta", TEXT);
+		// Pages are also indexed in their namespace's language, and queries
+		// analyzed for each, so only pages in that language match by stem.
+		let f_stemmed = language::LANGUAGES
+			.iter()
+			.map(|(code, _)| {
+				let options = TextOptions::default().set_indexing_options(
+					TextFieldIndexing::default()
+						.set_tokenizer(&language::tokenizer(code))
+						.set_index_option(
+							IndexRecordOption::WithFreqsAndPositions,
+						),
+				);
+				let title = schema_builder
+					.add_text_field(&format!("title_{code}"), options.clone());
+				let content = schema_builder
+					.add_text_field(&format!("content_{code}"), options);
+				(*code, title, content)
+			})
+			.collect::<Vec<_>>();
 		let schema = schema_builder.build();
-		let index = Index::create_in_ram(schema);
-		let mut index_writer = index.writer(Self::INDEX_SIZE_BYTES).unwrap();
-
-		// TODO: Obviously this won't scale forever, but I'm curious.
-		// TODO: Do not unwrap.
-		for (key, doc) in Page::list_all(db).await.map(Result::unwrap) {
-			let path = PageKey::dec(key).as_facet();
-			let doc = Page::dec(doc);
-			index_writer
-				.add_document(doc!(
-					f_path => path,
-					f_slug => doc.slug(),
-					f_title => doc.title(),
-					f_content => doc.content()
-				))
-				.unwrap();
+		// Panics: We're initializing, so prefer to crash here.
+		let index = open_index(path, schema).unwrap();
+		index.tokenizers().register(
+			Self::PREFIX_TOKENIZER,
+			TextAnalyzer::builder(
+				NgramTokenizer::prefix_only(1, Self::MAX_PREFIX).unwrap(),
+			)
+			.filter(LowerCaser)
+			.build(),
+		);
+		for (code, language) in language::LANGUAGES {
+			index.tokenizers().register(
+				&language::tokenizer(code),
+				language::analyzer(*language),
+			);
 		}
-		index_writer.commit().unwrap();
+		let reader = index
+			.reader_builder()
+			.reload_policy(ReloadPolicy::OnCommitWithDelay)
+			.try_into()
+			.unwrap();
 
-		let query_parser =
-			QueryParser::for_index(&index, vec![f_title, f_content]);
+		let mut default_fields = vec![f_title, f_content, f_summary];
+		for (_, title, content) in &f_stemmed {
+			default_fields.extend([*title, *content]);
+		}
+		let query_parser = QueryParser::for_index(&index, default_fields);
+		let languages = Namespace::list(&db)
+			.await
+			.into_iter()
+			.map(|ns| (ns.name, ns.language))
+			.collect();
 
-		Self {
-			index,
-			index_writer,
-			query_parser,
+		let fields = Fields {
+			f_key,
 			f_path,
 			f_slug,
 			f_title,
+			f_title_sort,
+			f_title_prefix,
+			f_modified,
 			f_content,
+			f_tags,
+			f_author,
+			f_read,
+			f_summary,
+			f_contact,
+			f_review_by,
+			f_meta,
+			f_stemmed,
+		};
+		let mut indexer =
+			Indexer::new(&index, db, fields.clone(), languages).unwrap();
+		indexer.catch_up().unwrap();
+		let (updates, queue) = mpsc::channel(Indexer::QUEUE_SIZE);
+		let metrics = Arc::new(Metrics::default());
+		indexer.spawn(queue, metrics.clone()).unwrap();
+
+		Self {
+			reader,
+			query_parser,
+			fields,
+			updates,
+			metrics,
+			related: Default::default(),
+		}
+	}
+
+	/// Indexes a page that was created or changed, soon.
+	pub async fn update_index(&self, ns: &str, page: Page) {
+		let key = PageKey::new(ns, page.slug());
+		self.send(Update::Page(key, Box::new(page))).await;
+	}
+
+	/// Sets the language a namespace's pages are searched in, indexing them
+	/// again.
+	pub async fn set_language(&self, ns: &str, code: Option<String>) {
+		self.send(Update::Language {
+			namespace: ns.to_string(),
+			code,
+		})
+		.await;
+	}
+
+	/// Indexes every page again from scratch.
+	pub async fn rebuild(&self) {
+		self.send(Update::Rebuild).await;
+	}
+
+	/// Indexes the updates still queued and stops the indexer, for shutdown.
+	pub async fn close(&self) {
+		let (done, stopped) = oneshot::channel();
+		if self.updates.send(Update::Stop(done)).await.is_ok() {
+			let _ = stopped.await;
+		}
+	}
+
+	/// How the indexer is keeping up, as names and values.
+	pub fn metrics(&self) -> Vec<(&'static str, u64)> {
+		let queued = self.updates.max_capacity() - self.updates.capacity();
+		let mut metrics = vec![
+			("search_queue_capacity", self.updates.max_capacity() as u64),
+			("search_queue_depth", queued as u64),
+		];
+		metrics.extend(self.metrics.read());
+		metrics
+	}
+
+	/// Queues an update for the indexer, waiting for room if it's behind.
+	async fn send(&self, update: Update) {
+		let update = match self.updates.try_send(update) {
+			Ok(()) => return,
+			Err(TrySendError::Full(update)) => {
+				self.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+				update
+			}
+			Err(TrySendError::Closed(_)) => {
+				error!("Search indexer stopped, the update is lost");
+				return;
+			}
+		};
+		if self.updates.send(update).await.is_err() {
+			error!("Search indexer stopped, the update is lost");
 		}
 	}
 
-	// TODO: Any user can search anything and see it, fix that.
+	/// Searches the pages `user` can read in the given namespaces, or only the
+	/// pages under `scope` ("namespace/slug") when set. Terms like
+	/// `title:word` search one field, and `ns:`, `author:`, `tag:` and
+	/// `updated:` terms filter the results, see `Filters`. Parts of the query
+	/// that don't parse are left out.
 	pub fn query(
 		&self,
 		query: &str,
 		namespaces: Vec<&str>,
-	) -> Vec<QueryResult> {
-		// TODO: I think reader should be long lived?
-		let searcher = self.index.reader().unwrap().searcher();
-		// TODO: Sanitize.
-		let q = self.query_parser.parse_query(query).unwrap();
-		let nsq = TermSetQuery::new(namespaces.iter().map(|ns| {
-			Term::from_facet(self.f_path, &Facet::from(&format!("/{ns}")))
-		}))
-		.box_clone();
-
-		let scoped_query =
-			BooleanQuery::new(vec![(Occur::Must, nsq), (Occur::Must, q)]);
-
-		let search_results = searcher
-			.search(&scoped_query, &TopDocs::with_limit(16))
-			.unwrap();
+		scope: Option<&str>,
+		user: &Option<User>,
+		sort: Sort,
+		paging: Paging,
+	) -> Hits {
+		let searcher = self.reader.searcher();
+		let (text, filters) = Filters::split(query);
+		let mut errors = filters.errors;
+		let q = if text.trim().is_empty() {
+			AllQuery.box_clone()
+		} else {
+			let (q, parse_errors) =
+				self.query_parser.parse_query_lenient(&text);
+			errors.extend(parse_errors.iter().map(ToString::to_string));
+			errors.dedup();
+			match self.fuzzy_query(&text) {
+				Some(fuzzy) => Box::new(BooleanQuery::new(vec![
+					(Occur::Should, q),
+					(
+						Occur::Should,
+						Box::new(BoostQuery::new(fuzzy, Self::FUZZY_BOOST)),
+					),
+				])),
+				None => q,
+			}
+		};
+		let mut clauses = vec![
+			(Occur::Must, self.namespace_filter(&namespaces)),
+			(Occur::Must, q.box_clone()),
+		];
+		if let Some(access) = self.access_filter(user) {
+			clauses.push((Occur::Must, access));
+		}
+		if let Some(scope) = scope {
+			let subtree = Facet::from_text(&format!("/{scope}"))
+				.unwrap_or_else(|_| Facet::root());
+			let subtree_query: Box<dyn Query> = Box::new(TermQuery::new(
+				Term::from_facet(self.fields.f_path, &subtree),
+				IndexRecordOption::Basic,
+			));
+			clauses.push((Occur::Must, subtree_query));
+		}
+		if !filters.namespaces.is_empty() {
+			let ns_filter =
+				TermSetQuery::new(filters.namespaces.iter().map(|ns| {
+					Term::from_facet(
+						self.fields.f_path,
+						&Facet::from_path([ns]),
+					)
+				}));
+			clauses.push((Occur::Must, Box::new(ns_filter)));
+		}
+		if !filters.authors.is_empty() {
+			let author_filter =
+				TermSetQuery::new(filters.authors.iter().map(|author| {
+					Term::from_field_text(self.fields.f_author, author)
+				}));
+			clauses.push((Occur::Must, Box::new(author_filter)));
+		}
+		for tag in filters.tags {
+			let tag_query: Box<dyn Query> = Box::new(TermQuery::new(
+				Term::from_facet(self.fields.f_tags, &Facet::from_path([tag])),
+				IndexRecordOption::Basic,
+			));
+			clauses.push((Occur::Must, tag_query));
+		}
+		for (from, to) in filters.updated {
+			let updated_query =
+				RangeQuery::new_date_bounds(Self::MODIFIED.into(), from, to);
+			clauses.push((Occur::Must, Box::new(updated_query)));
+		}
+		let scoped_query = BooleanQuery::new(clauses);
+
+		let top_docs =
+			TopDocs::with_limit(paging.limit).and_offset(paging.offset);
+		// TODO: Handle error.
+		let (total, search_results): (usize, Vec<DocAddress>) = match sort {
+			Sort::Relevance => searcher
+				.search(&scoped_query, &(Count, top_docs))
+				.map(|(total, docs)| (total, addresses(docs))),
+			Sort::Title => {
+				let by_title = top_docs.custom_score(title_order);
+				searcher
+					.search(&scoped_query, &(Count, by_title))
+					.map(|(total, docs)| (total, addresses(docs)))
+			}
+			Sort::Modified => {
+				let by_modified = top_docs.order_by_fast_field::<DateTime>(
+					Self::MODIFIED,
+					Order::Desc,
+				);
+				searcher
+					.search(&scoped_query, &(Count, by_modified))
+					.map(|(total, docs)| (total, addresses(docs)))
+			}
+		}
+		.unwrap();
+		let mut snippets =
+			SnippetGenerator::create(&searcher, &*q, self.fields.f_content)
+				.unwrap();
+		snippets.set_max_num_chars(Self::SNIPPET_CHARS);
 
 		let mut results = vec![];
-		for (_score, doc_address) in search_results {
+		for doc_address in search_results {
 			let doc = searcher.doc::<TantivyDocument>(doc_address).unwrap();
-			let path = doc
-				.get_first(self.f_path)
-				.unwrap()
-				.as_facet()
-				.unwrap()
-				.to_path_string();
-			let (ns, slug) = &path[1..] // remove leading slash
-				.split_once('/')
-				.unwrap();
+			let (namespace, slug) = self.page_of(&doc);
 			results.push(QueryResult {
-				namespace: ns.to_string(),
-				slug: slug.to_string(),
-				title: doc
-					.get_first(self.f_title)
-					.unwrap()
-					.as_str()
-					.unwrap()
-					.to_string(),
+				namespace,
+				slug,
+				title: self.title_of(&doc),
+				// Escaped apart from the highlighting.
+				snippet: snippets.snippet_from_doc(&doc).to_html(),
 			})
 		}
 
-		results
+		let did_you_mean = if total == 0 {
+			let mut readable =
+				vec![(Occur::Must, self.namespace_filter(&namespaces))];
+			if let Some(access) = self.access_filter(user) {
+				readable.push((Occur::Must, access));
+			}
+			self.did_you_mean(&searcher, query, &BooleanQuery::new(readable))
+		} else {
+			None
+		};
+
+		Hits {
+			total,
+			results,
+			errors,
+			did_you_mean,
+		}
 	}
 
-	pub fn update_index(&mut self, ns: &str, doc: &Page) {
-		// TODO: This is will remove all docs with this slug, not just the one
-		//       in this namespace.
-		let path = Facet::from_text(&format!("/{ns}/{}", doc.slug())).unwrap();
-		self.index_writer
-			.delete_term(Term::from_facet(self.f_path, &path));
-		self.index_writer
-			.add_document(doc!(
-				self.f_path => path,
-				self.f_slug => doc.slug(),
-				self.f_title => doc.title(),
-				self.f_content => doc.content()
-			))
-			// TODO: Handle error.
-			.unwrap();
+	/// Matches the words of a query with a typo or a few, see `max_typos`.
+	/// `None` if there are no words long enough.
+	fn fuzzy_query(&self, text: &str) -> Option<Box<dyn Query>> {
+		let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![];
+		for word in plain_words(text) {
+			let typos = max_typos(&word);
+			if typos == 0 {
+				continue;
+			}
+			for field in [self.fields.f_title, self.fields.f_content] {
+				let term = Term::from_field_text(field, &word);
+				clauses.push((
+					Occur::Should,
+					Box::new(FuzzyTermQuery::new(term, typos, true)),
+				));
+			}
+		}
+		(!clauses.is_empty())
+			.then(|| Box::new(BooleanQuery::new(clauses)) as Box<dyn Query>)
+	}
+
+	/// The query with each word replaced by the closest one in the pages
+	/// `readable` matches, if any were.
+	fn did_you_mean(
+		&self,
+		searcher: &Searcher,
+		query: &str,
+		readable: &dyn Query,
+	) -> Option<String> {
+		let mut corrected = false;
+		let words = query
+			.split_whitespace()
+			.map(|term| {
+				if !is_plain(term) {
+					return term.to_string();
+				}
+				let word = term.to_lowercase();
+				match self.closest_term(searcher, &word, readable) {
+					Some(closest) if closest != word => {
+						corrected = true;
+						closest
+					}
+					_ => term.to_string(),
+				}
+			})
+			.collect::<Vec<_>>();
+		corrected.then(|| words.join(" "))
+	}
 
+	/// The title or content word nearest `word` in the pages `readable`
+	/// matches, and most common among those. Typos in the first letter aren't
+	/// looked for.
+	fn closest_term(
+		&self,
+		searcher: &Searcher,
+		word: &str,
+		readable: &dyn Query,
+	) -> Option<String> {
+		// One more than searches allow, so there's something to suggest when
+		// they found nothing.
+		let typos = usize::from(max_typos(word)) + 1;
+		let first = word.chars().next()?;
+		let next = char::from_u32(first as u32 + 1)?;
+
+		// Word => pages it's in.
+		let mut candidates = HashMap::<String, u32>::new();
+		for segment in searcher.segment_readers() {
+			for field in [self.fields.f_title, self.fields.f_content] {
+				let index = segment.inverted_index(field).ok()?;
+				let mut terms = index
+					.terms()
+					.range()
+					.ge(first.to_string())
+					.lt(next.to_string())
+					.into_stream()
+					.ok()?;
+				while terms.advance() {
+					if let Ok(term) = std::str::from_utf8(terms.key()) {
+						*candidates.entry(term.to_string()).or_default() +=
+							terms.value().doc_freq;
+					}
+				}
+			}
+		}
+
+		let mut candidates = candidates
+			.into_iter()
+			.filter_map(|(term, pages)| {
+				let distance = edit_distance(word, &term);
+				(distance <= typos).then_some((distance, Reverse(pages), term))
+			})
+			.collect::<Vec<_>>();
+		candidates.sort();
+
+		// Counted again in the pages the user can read, so words only found in
+		// the rest aren't given away.
+		candidates
+			.into_iter()
+			.take(Self::MAX_CANDIDATES)
+			.filter_map(|(distance, _, term)| {
+				let pages = self.pages_with(searcher, &term, readable);
+				(pages > 0).then_some((distance, Reverse(pages), term))
+			})
+			.min()
+			.map(|(.., term)| term)
+	}
+
+	/// How many of the pages `readable` matches have a word in their title or
+	/// content.
+	fn pages_with(
+		&self,
+		searcher: &Searcher,
+		word: &str,
+		readable: &dyn Query,
+	) -> usize {
+		let in_page = [self.fields.f_title, self.fields.f_content]
+			.into_iter()
+			.map(|field| -> (Occur, Box<dyn Query>) {
+				let term = Term::from_field_text(field, word);
+				(
+					Occur::Should,
+					Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+				)
+			})
+			.collect();
+		let query = BooleanQuery::new(vec![
+			(Occur::Must, readable.box_clone()),
+			(Occur::Must, Box::new(BooleanQuery::new(in_page))),
+		]);
+		searcher.search(&query, &Count).unwrap_or_default()
+	}
+
+	/// Titles of pages `user` can read in the given namespaces with a word
+	/// starting with each word of `prefix`, for the search bar to offer as
+	/// they type. E.g. "data run" finds "Database runbook".
+	pub fn suggest(
+		&self,
+		prefix: &str,
+		namespaces: Vec<&str>,
+		user: &Option<User>,
+	) -> Vec<Suggestion> {
+		let mut clauses =
+			vec![(Occur::Must, self.namespace_filter(&namespaces))];
+		for word in words(prefix) {
+			// Longer words were indexed up to this long.
+			let word = word
+				.chars()
+				.take(Self::MAX_PREFIX)
+				.collect::<String>()
+				.to_lowercase();
+			let prefix_query: Box<dyn Query> = Box::new(TermQuery::new(
+				Term::from_field_text(self.fields.f_title_prefix, &word),
+				IndexRecordOption::Basic,
+			));
+			clauses.push((Occur::Must, prefix_query));
+		}
+		if clauses.len() == 1 {
+			return vec![];
+		}
+		if let Some(access) = self.access_filter(user) {
+			clauses.push((Occur::Must, access));
+		}
+
+		let searcher = self.reader.searcher();
 		// TODO: Handle error.
-		self.index_writer.commit().unwrap();
+		let top_docs = searcher
+			.search(
+				&BooleanQuery::new(clauses),
+				&TopDocs::with_limit(Self::SUGGESTIONS),
+			)
+			.unwrap();
+
+		let mut suggestions = vec![];
+		for (_score, doc_address) in top_docs {
+			let doc = searcher.doc::<TantivyDocument>(doc_address).unwrap();
+			let (ns, slug) = self.page_of(&doc);
+			suggestions.push(Suggestion {
+				title: self.title_of(&doc),
+				url: page::url(&ns, &slug),
+			});
+		}
+		suggestions
+	}
+
+	/// Pages like a page, by the words in its title and content and by its
+	/// tags, among those `user` can read in the given namespaces.
+	pub fn related(
+		&self,
+		key: &PageKey,
+		page: &Page,
+		namespaces: Vec<&str>,
+		user: &Option<User>,
+	) -> Vec<Suggestion> {
+		let searcher = self.reader.searcher();
+		let cache_key = RelatedKey {
+			page: key.as_str().to_string(),
+			seq: page.seq(),
+			generation: searcher.generation().generation_id(),
+			user: user.as_ref().map(|user| user.name.clone()),
+			namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+		};
+		if let Some(related) = self.related.lock().unwrap().get(&cache_key) {
+			return related.clone();
+		}
+
+		// Words in most pages, like "the", don't say much about any.
+		let common = (searcher.num_docs() / 2).max(Self::RELATED_MIN_PAGES);
+		let like_this = MoreLikeThisQuery::builder()
+			.with_min_doc_frequency(Self::RELATED_MIN_PAGES)
+			.with_max_doc_frequency(common)
+			.with_min_term_frequency(1)
+			.with_min_word_length(3)
+			.with_document_fields(vec![
+				(self.fields.f_title, vec![page.title().to_string().into()]),
+				(self.fields.f_content, vec![page.body().to_string().into()]),
+			]);
+		let mut similar: Vec<(Occur, Box<dyn Query>)> =
+			vec![(Occur::Should, Box::new(like_this))];
+		for tag in page.tags() {
+			let tag_query = TermQuery::new(
+				Term::from_facet(self.fields.f_tags, &Facet::from_path([tag])),
+				IndexRecordOption::Basic,
+			);
+			similar.push((
+				Occur::Should,
+				Box::new(BoostQuery::new(
+					Box::new(tag_query),
+					Self::RELATED_TAG_BOOST,
+				)),
+			));
+		}
+
+		let itself: Box<dyn Query> = Box::new(TermQuery::new(
+			Term::from_field_text(self.fields.f_key, key.as_str()),
+			IndexRecordOption::Basic,
+		));
+		let mut clauses = vec![
+			(Occur::Must, self.namespace_filter(&namespaces)),
+			(Occur::Must, Box::new(BooleanQuery::new(similar))),
+			(Occur::MustNot, itself),
+		];
+		if let Some(access) = self.access_filter(user) {
+			clauses.push((Occur::Must, access));
+		}
+
+		// Not worth failing the page over.
+		let Ok(top_docs) = searcher.search(
+			&BooleanQuery::new(clauses),
+			&TopDocs::with_limit(Self::RELATED),
+		) else {
+			return vec![];
+		};
+
+		let mut related = vec![];
+		for (_score, doc_address) in top_docs {
+			let Ok(doc) = searcher.doc::<TantivyDocument>(doc_address) else {
+				return vec![];
+			};
+			let (ns, slug) = self.page_of(&doc);
+			related.push(Suggestion {
+				title: self.title_of(&doc),
+				url: page::url(&ns, &slug),
+			});
+		}
+
+		let mut cache = self.related.lock().unwrap();
+		if cache.len() >= Self::RELATED_CACHE {
+			cache.clear();
+		}
+		cache.insert(cache_key, related.clone());
+		related
+	}
+
+	fn namespace_filter(&self, namespaces: &[&str]) -> Box<dyn Query> {
+		Box::new(TermSetQuery::new(namespaces.iter().map(|ns| {
+			Term::from_facet(self.fields.f_path, &Facet::from_path([ns]))
+		})))
+	}
+
+	/// Namespace and slug of a found page.
+	fn page_of(&self, doc: &TantivyDocument) -> (String, String) {
+		let path = doc
+			.get_first(self.fields.f_path)
+			.unwrap()
+			.as_facet()
+			.unwrap()
+			.to_path_string();
+		let (ns, slug) = path[1..] // remove leading slash
+			.split_once('/')
+			.unwrap();
+		(ns.to_string(), slug.to_string())
+	}
+
+	fn title_of(&self, doc: &TantivyDocument) -> String {
+		doc.get_first(self.fields.f_title)
+			.unwrap()
+			.as_str()
+			.unwrap()
+			.to_string()
+	}
+
+	/// Matches the pages `user` can read in namespaces they can read, the same
+	/// as `Page::user_has_access`. `None` if they can read all of them.
+	fn access_filter(&self, user: &Option<User>) -> Option<Box<dyn Query>> {
+		if user.as_ref().is_some_and(|user| user.name == User::META) {
+			return None;
+		}
+
+		let term = |field, value: &str| -> Box<dyn Query> {
+			Box::new(TermQuery::new(
+				Term::from_field_text(field, value),
+				IndexRecordOption::Basic,
+			))
+		};
+		let mut readable =
+			vec![(Occur::Should, term(self.fields.f_read, Self::READ_OTHERS))];
+		if let Some(user) = user {
+			readable
+				.push((Occur::Should, term(self.fields.f_author, &user.name)));
+			let own_namespaces =
+				TermSetQuery::new(user.namespaces.iter().map(|ns| {
+					Term::from_facet(
+						self.fields.f_path,
+						&Facet::from_path([ns]),
+					)
+				}));
+			let namespace_readable = BooleanQuery::new(vec![
+				(Occur::Must, term(self.fields.f_read, Self::READ_NAMESPACE)),
+				(Occur::Must, Box::new(own_namespaces)),
+			]);
+			readable.push((Occur::Should, Box::new(namespace_readable)));
+		}
+		// Only filters, so it doesn't change the ranking.
+		Some(Box::new(ConstScoreQuery::new(
+			Box::new(BooleanQuery::new(readable)),
+			0.0,
+		)))
+	}
+}
+
+/// Sorts a segment's documents by `title_sort`. Top docs have the highest
+/// scores, so titles are reversed to come out A to Z.
+fn title_order(segment: &SegmentReader) -> impl Fn(u32) -> Reverse<String> {
+	let titles = segment
+		.fast_fields()
+		.str(SearchContext::TITLE_SORT)
+		.ok()
+		.flatten();
+	move |doc| {
+		let mut title = String::new();
+		if let Some(ref titles) = titles {
+			if let Some(ord) = titles.term_ords(doc).next() {
+				// An unreadable title sorts like an empty one.
+				let _ = titles.ord_to_str(ord, &mut title);
+			}
+		}
+		Reverse(title)
+	}
+}
+
+/// Words searched as typed, not in a phrase, for a field or left out.
+fn plain_words(text: &str) -> impl Iterator<Item = String> + '_ {
+	text.split_whitespace()
+		.filter(|term| is_plain(term))
+		.map(str::to_lowercase)
+}
+
+fn is_plain(term: &str) -> bool {
+	term.chars().all(char::is_alphanumeric)
+}
+
+/// How many typos a word may have, more for longer words. Short words are
+/// too close to too many others.
+fn max_typos(word: &str) -> u8 {
+	match word.chars().count() {
+		0..=3 => 0,
+		4..=7 => 1,
+		_ => 2,
+	}
+}
+
+/// Edits between two words, swapping two letters next to each other counting
+/// as one like in `FuzzyTermQuery`.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let a = a.chars().collect::<Vec<_>>();
+	let b = b.chars().collect::<Vec<_>>();
+	// Rows of the distance table, the one two back for swaps.
+	let mut before = vec![0; b.len() + 1];
+	let mut previous = (0..=b.len()).collect::<Vec<_>>();
+	for i in 0..a.len() {
+		let mut current = vec![i + 1; b.len() + 1];
+		for j in 0..b.len() {
+			let substitution = previous[j] + usize::from(a[i] != b[j]);
+			let mut distance =
+				substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+			if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+				distance = distance.min(before[j - 1] + 1);
+			}
+			current[j + 1] = distance;
+		}
+		before = std::mem::replace(&mut previous, current);
+	}
+	previous[b.len()]
+}
+
+pub(super) fn words(text: &str) -> impl Iterator<Item = &str> {
+	text.split(|c: char| !c.is_alphanumeric())
+		.filter(|word| !word.is_empty())
+}
+
+fn addresses<T>(docs: Vec<(T, DocAddress)>) -> Vec<DocAddress> {
+	docs.into_iter().map(|(_, address)| address).collect()
+}
+
+/// Opens the index in a directory, starting over if it was made with another
+/// schema.
+fn open_index(path: &str, schema: Schema) -> Result<Index, TantivyError> {
+	fs::create_dir_all(path)?;
+	match Index::open_or_create(MmapDirectory::open(path)?, schema.clone()) {
+		Err(TantivyError::SchemaError(_)) => {
+			warn!("Search schema changed, rebuilding the index");
+			fs::remove_dir_all(path)?;
+			fs::create_dir_all(path)?;
+			Index::create_in_dir(path, schema)
+		}
+		index => index,
+	}
+}
+
+/// Filters written in a query as `prefix:value`, e.g. "runbook ns:ops
+/// tag:database" is searched as "runbook" among pages in namespace "ops" that
+/// are tagged "database".
+#[derive(Default)]
+struct Filters {
+	/// `ns:name`, any of them.
+	namespaces: Vec<String>,
+	/// `author:name`, any of them.
+	authors: Vec<String>,
+	/// `tag:name` or `tags:name`, all of them.
+	tags: Vec<String>,
+	/// `updated:2026-01-01` for that day, or with `>`, `>=`, `<` or `<=` for
+	/// days after or before it.
+	updated: Vec<(Bound<DateTime>, Bound<DateTime>)>,
+	errors: Vec<String>,
+}
+
+impl Filters {
+	/// Splits the filters out of a query, leaving its text.
+	fn split(query: &str) -> (String, Self) {
+		let mut filters = Self::default();
+		let mut text = vec![];
+		for term in query.split_whitespace() {
+			let Some((prefix, value)) =
+				term.split_once(':').filter(|(_, value)| !value.is_empty())
+			else {
+				text.push(term);
+				continue;
+			};
+			match prefix {
+				"ns" => filters.namespaces.push(value.to_string()),
+				"author" => filters.authors.push(value.to_string()),
+				"tag" | "tags" => filters.tags.push(page::normalize_tag(value)),
+				"updated" => match updated_bounds(value) {
+					Some(bounds) => filters.updated.push(bounds),
+					None => filters.errors.push(format!(
+						"Left out \"{term}\", dates are written like \
+						 updated:>2026-01-01."
+					)),
+				},
+				_ => text.push(term),
+			}
+		}
+		(text.join(" "), filters)
+	}
+}
+
+/// Bounds for an `updated:` filter's value, e.g. ">2026-01-01" is from the
+/// day after.
+fn updated_bounds(value: &str) -> Option<(Bound<DateTime>, Bound<DateTime>)> {
+	let (op, date) = [">=", "<=", ">", "<"]
+		.into_iter()
+		.find_map(|op| value.strip_prefix(op).map(|date| (op, date)))
+		.unwrap_or(("", value));
+	let date = front_matter::parse_date(date)?;
+	let midnight = |date: time::Date| {
+		DateTime::from_timestamp_secs(
+			date.midnight().assume_utc().unix_timestamp(),
+		)
+	};
+	let (start, end) = (midnight(date), midnight(date.next_day()?));
+
+	Some(match op {
+		">" => (Bound::Included(end), Bound::Unbounded),
+		">=" => (Bound::Included(start), Bound::Unbounded),
+		"<" => (Bound::Unbounded, Bound::Excluded(start)),
+		"<=" => (Bound::Unbounded, Bound::Excluded(end)),
+		_ => (Bound::Included(start), Bound::Excluded(end)),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn edit_distance_counts_edits() {
+		assert_eq!(edit_distance("runbook", "runbook"), 0);
+		assert_eq!(edit_distance("", "abc"), 3);
+		assert_eq!(edit_distance("kitten", "sitting"), 3);
+		assert_eq!(edit_distance("databse", "database"), 1);
+	}
+
+	#[test]
+	fn edit_distance_counts_a_swap_once() {
+		assert_eq!(edit_distance("passwrod", "password"), 1);
+		assert_eq!(edit_distance("ab", "ba"), 1);
+		// Letters can't be edited again once swapped.
+		assert_eq!(edit_distance("abc", "ca"), 3);
+	}
+
+	#[test]
+	fn max_typos_grows_with_length() {
+		assert_eq!(max_typos("ops"), 0);
+		assert_eq!(max_typos("disk"), 1);
+		assert_eq!(max_typos("runbook"), 1);
+		assert_eq!(max_typos("postgresql"), 2);
+		// Letters, not bytes.
+		assert_eq!(max_typos("été"), 0);
+	}
+
+	#[test]
+	fn plain_words_skips_fields_and_phrases() {
+		let words = plain_words("Restore \"last night\" title:backup DB")
+			.collect::<Vec<_>>();
+		assert_eq!(words, ["restore", "db"]);
+	}
+
+	#[test]
+	fn filters_split_out_of_the_text() {
+		let (text, filters) = Filters::split(
+			"restore ns:ops tag:Incident author:dba updated:>2026-01-01",
+		);
+		assert_eq!(text, "restore");
+		assert_eq!(filters.namespaces, ["ops"]);
+		assert_eq!(filters.tags, ["incident"]);
+		assert_eq!(filters.authors, ["dba"]);
+		assert_eq!(filters.updated.len(), 1);
+		assert!(filters.errors.is_empty());
+	}
+
+	#[test]
+	fn filters_leave_unknown_and_empty_prefixes() {
+		let (text, filters) = Filters::split("title:backup ns: 10:30");
+		assert_eq!(text, "title:backup ns: 10:30");
+		assert!(filters.namespaces.is_empty());
+	}
+
+	#[test]
+	fn filters_report_bad_dates() {
+		let (text, filters) = Filters::split("restore updated:yesterday");
+		assert_eq!(text, "restore");
+		assert!(filters.updated.is_empty());
+		assert_eq!(filters.errors.len(), 1);
 	}
 }
@@ -0,0 +1,319 @@
+use std::{
+	collections::HashMap,
+	io,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	thread,
+	time::Instant,
+};
+
+use futures::executor::block_on;
+use rocksdb::TransactionDB;
+use tantivy::{
+	doc,
+	schema::{Facet, Field},
+	DateTime, Index, IndexWriter, TantivyDocument, Term,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+use super::{
+	context::{words, Fields, SearchContext},
+	language,
+};
+use crate::{
+	auth,
+	auth::has_access,
+	encoding::DbDecode,
+	errors::WkError,
+	front_matter,
+	page::{Page, PageKey},
+};
+
+/// A change for the indexer to make, see `Indexer::run`.
+pub(super) enum Update {
+	/// A page was created or changed.
+	Page(PageKey, Box<Page>),
+	/// A namespace's pages are searched in another language now.
+	Language {
+		namespace: String,
+		code: Option<String>,
+	},
+	/// Every page, to index from scratch.
+	Rebuild,
+	/// Index what's queued and stop, then say so.
+	Stop(oneshot::Sender<()>),
+}
+
+/// Counts of what the indexer did, since start.
+#[derive(Default)]
+pub(super) struct Metrics {
+	/// Updates that waited for room in a full queue.
+	pub blocked: AtomicU64,
+	pub updates: AtomicU64,
+	pub failed: AtomicU64,
+	pub commits: AtomicU64,
+	pub last_commit_ms: AtomicU64,
+}
+
+impl Metrics {
+	pub fn read(&self) -> [(&'static str, u64); 5] {
+		let read = |count: &AtomicU64| count.load(Ordering::Relaxed);
+		[
+			("search_blocked_total", read(&self.blocked)),
+			("search_updates_total", read(&self.updates)),
+			("search_failed_total", read(&self.failed)),
+			("search_commits_total", read(&self.commits)),
+			("search_last_commit_ms", read(&self.last_commit_ms)),
+		]
+	}
+}
+
+/// Owns the index writer. Pages are written to the index on its own thread,
+/// so requests neither wait for commits nor hold up searches.
+pub(super) struct Indexer {
+	writer: IndexWriter,
+	db: Arc<TransactionDB>,
+	fields: Fields,
+	/// Namespace => its language code, `None` for no language.
+	languages: HashMap<String, Option<String>>,
+	/// Pages added since the last commit, with the `Page::seq` they were
+	/// added at. Once committed they're no longer `Page::unindexed`.
+	uncommitted: Vec<(PageKey, u64)>,
+}
+
+impl Indexer {
+	const INDEX_SIZE_BYTES: usize = 0xF00_000; // 15MB is the minimum.
+	/// Updates waiting to be indexed, past this senders wait.
+	pub const QUEUE_SIZE: usize = 1024;
+	/// Most updates put in one commit.
+	const MAX_BATCH: usize = 256;
+	/// Stored with each commit. Indexes without it are new, or kept track
+	/// differently, and are caught up in full.
+	const PAYLOAD: &'static str = "unindexed";
+
+	pub fn new(
+		index: &Index,
+		db: Arc<TransactionDB>,
+		fields: Fields,
+		languages: HashMap<String, Option<String>>,
+	) -> Result<Self, WkError> {
+		Ok(Self {
+			writer: index.writer(Self::INDEX_SIZE_BYTES)?,
+			db,
+			fields,
+			languages,
+			uncommitted: vec![],
+		})
+	}
+
+	/// Indexes the pages written since the index last committed them, or all
+	/// of them if the index is new, and commits.
+	pub fn catch_up(&mut self) -> Result<(), WkError> {
+		let mut count = 0;
+		let payload = self.writer.index().load_metas()?.payload;
+		if payload.as_deref() != Some(Self::PAYLOAD) {
+			let db = self.db.clone();
+			for page in block_on(Page::list_all(&db)) {
+				let (key, page) = page?;
+				self.add(&PageKey::from_bytes(key), &Page::dec(page))?;
+				count += 1;
+			}
+		} else {
+			for (key, page) in Page::unindexed(&self.db)? {
+				self.add(&key, &page)?;
+				count += 1;
+			}
+		}
+		info!("Indexed {count} pages");
+		self.commit()
+	}
+
+	/// Starts indexing the updates sent to `queue`, until they stop coming.
+	pub fn spawn(
+		self,
+		queue: mpsc::Receiver<Update>,
+		metrics: Arc<Metrics>,
+	) -> io::Result<()> {
+		thread::Builder::new()
+			.name("indexer".to_string())
+			.spawn(move || self.run(queue, metrics))?;
+		Ok(())
+	}
+
+	/// Commits once for all the updates waiting, so a burst of edits doesn't
+	/// make a commit each. Updates that fail stay `Page::unindexed`, for the
+	/// next start to try again.
+	fn run(mut self, mut queue: mpsc::Receiver<Update>, metrics: Arc<Metrics>) {
+		let mut stopped = None;
+		while let Some(update) = queue.blocking_recv() {
+			let mut batch = vec![update];
+			while batch.len() < Self::MAX_BATCH {
+				match queue.try_recv() {
+					Ok(update) => batch.push(update),
+					Err(_) => break,
+				}
+			}
+
+			if let Some(index) = batch
+				.iter()
+				.position(|update| matches!(update, Update::Stop(_)))
+			{
+				// Nothing more is taken, what's queued is still indexed.
+				queue.close();
+				if let Update::Stop(done) = batch.remove(index) {
+					stopped = Some(done);
+				}
+			}
+			if batch.is_empty() {
+				continue;
+			}
+
+			let count = batch.len() as u64;
+			for update in batch {
+				if let Err(e) = self.apply(update) {
+					error!("Can't index update: {e}");
+					metrics.failed.fetch_add(1, Ordering::Relaxed);
+				}
+			}
+			metrics.updates.fetch_add(count, Ordering::Relaxed);
+
+			let start = Instant::now();
+			if let Err(e) = self.commit() {
+				error!("Can't commit {count} updates to the index: {e}");
+				metrics.failed.fetch_add(count, Ordering::Relaxed);
+				continue;
+			}
+			let took = start.elapsed().as_millis() as u64;
+			debug!("Committed {count} updates to the index in {took}ms");
+			metrics.commits.fetch_add(1, Ordering::Relaxed);
+			metrics.last_commit_ms.store(took, Ordering::Relaxed);
+		}
+		info!("Search indexer stopped");
+		if let Some(done) = stopped {
+			let _ = done.send(());
+		}
+	}
+
+	fn apply(&mut self, update: Update) -> Result<(), WkError> {
+		match update {
+			Update::Page(key, page) => self.add(&key, &page),
+			// Pages are read when the update's taken, not when it's sent, so
+			// edits queued before it aren't undone by older copies.
+			Update::Language { namespace, code } => {
+				self.languages.insert(namespace.clone(), code);
+				let db = self.db.clone();
+				for page in block_on(Page::list(&db, &namespace)) {
+					self.add(&PageKey::new(&namespace, page.slug()), &page)?;
+				}
+				Ok(())
+			}
+			Update::Rebuild => {
+				self.writer.delete_all_documents()?;
+				let db = self.db.clone();
+				for page in block_on(Page::list_all(&db)) {
+					let (key, page) = page?;
+					self.add(&PageKey::from_bytes(key), &Page::dec(page))?;
+				}
+				Ok(())
+			}
+			Update::Stop(_) => unreachable!("taken out of the batch"),
+		}
+	}
+
+	/// Replaces a page's document, to be committed.
+	fn add(&mut self, key: &PageKey, page: &Page) -> Result<(), WkError> {
+		self.writer.delete_term(Term::from_field_text(
+			self.fields.f_key,
+			key.as_str(),
+		));
+		self.writer.add_document(self.document(key, page))?;
+		self.uncommitted.push((key.clone(), page.seq()));
+		Ok(())
+	}
+
+	/// Commits the pages added, then marks them indexed. Pages that failed to
+	/// add stay unindexed, for the next start to try again.
+	fn commit(&mut self) -> Result<(), WkError> {
+		let mut commit = self.writer.prepare_commit()?;
+		commit.set_payload(Self::PAYLOAD);
+		commit.commit()?;
+		for (key, seq) in self.uncommitted.drain(..) {
+			if let Err(e) = Page::set_indexed(&self.db, &key, seq) {
+				// Indexed again on the next start, which does no harm.
+				warn!("Can't mark {} indexed: {e}", key.as_str());
+			}
+		}
+		Ok(())
+	}
+
+	/// Title and content fields in a namespace's language, if it has one.
+	fn stemmed_fields(&self, ns: &str) -> Option<(Field, Field)> {
+		// Namespaces made since start have the default.
+		let code = match self.languages.get(ns) {
+			Some(code) => code.as_deref()?,
+			None => language::DEFAULT,
+		};
+		self.fields
+			.f_stemmed
+			.iter()
+			.find(|(stemmed, ..)| *stemmed == code)
+			.map(|(_, title, content)| (*title, *content))
+	}
+
+	fn document(&self, key: &PageKey, page: &Page) -> TantivyDocument {
+		let fields = &self.fields;
+		let mut document = doc!(
+			fields.f_key => key.as_str(),
+			fields.f_path => key.as_facet(),
+			fields.f_slug => page.slug(),
+			fields.f_title => page.title(),
+			fields.f_title_sort => page.title().to_lowercase(),
+			fields.f_modified => DateTime::from_timestamp_secs(page.modified()),
+			fields.f_content => page.body()
+		);
+		for word in words(page.title()) {
+			document.add_text(fields.f_title_prefix, word);
+		}
+		if let Some((title, content)) = self.stemmed_fields(key.namespace()) {
+			document.add_text(title, page.title());
+			document.add_text(content, page.body());
+		}
+		for tag in page.tags() {
+			document.add_facet(fields.f_tags, Facet::from_path([tag]));
+		}
+		if let Some(owner) = page.owner() {
+			document.add_text(fields.f_author, owner);
+		}
+		if has_access(page.mode, auth::OTHERS, auth::READ) {
+			document.add_text(fields.f_read, SearchContext::READ_OTHERS);
+		}
+		if has_access(page.mode, auth::NAMESPACE, auth::READ) {
+			document.add_text(fields.f_read, SearchContext::READ_NAMESPACE);
+		}
+
+		let meta = page.meta();
+		if let Some(ref summary) = meta.summary {
+			document.add_text(fields.f_summary, summary);
+		}
+		if let Some(ref contact) = meta.contact {
+			document.add_text(fields.f_contact, contact);
+		}
+		if let Some(date) =
+			meta.review_by.as_deref().and_then(front_matter::parse_date)
+		{
+			let midnight = date.midnight().assume_utc().unix_timestamp();
+			document.add_date(
+				fields.f_review_by,
+				DateTime::from_timestamp_secs(midnight),
+			);
+		}
+		for (key, value) in &meta.extra {
+			document.add_text(fields.f_meta, format!("{key} {value}"));
+		}
+
+		document
+	}
+}
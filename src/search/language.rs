@@ -0,0 +1,44 @@
+use tantivy::tokenizer::{
+	Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer,
+	StopWordFilter, TextAnalyzer,
+};
+
+/// Languages a namespace's pages can be searched in, by code. Words are
+/// matched by their stem, e.g. "deploying" finds "deploy", and stop words like
+/// "the" are left out.
+pub const LANGUAGES: &[(&str, Language)] = &[
+	("en", Language::English),
+	("de", Language::German),
+	("fr", Language::French),
+	("es", Language::Spanish),
+	("it", Language::Italian),
+	("nl", Language::Dutch),
+	("pt", Language::Portuguese),
+	("sv", Language::Swedish),
+];
+
+/// Language of new namespaces.
+pub const DEFAULT: &str = "en";
+
+/// Longer tokens are dropped, like tantivy's default tokenizer.
+const MAX_TOKEN_BYTES: usize = 40;
+
+pub fn is_supported(code: &str) -> bool {
+	LANGUAGES.iter().any(|(supported, _)| *supported == code)
+}
+
+/// Name the language's analyzer is registered under.
+pub fn tokenizer(code: &str) -> String {
+	format!("lang_{code}")
+}
+
+pub fn analyzer(language: Language) -> TextAnalyzer {
+	let mut builder = TextAnalyzer::builder(SimpleTokenizer::default())
+		.filter(RemoveLongFilter::limit(MAX_TOKEN_BYTES))
+		.filter(LowerCaser)
+		.dynamic();
+	if let Some(stop_words) = StopWordFilter::new(language) {
+		builder = builder.filter_dynamic(stop_words);
+	}
+	builder.filter_dynamic(Stemmer::new(language)).build()
+}
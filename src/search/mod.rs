@@ -1,33 +1,161 @@
 mod context;
+mod indexer;
+pub mod language;
 
 use std::sync::Arc;
 
 use askama::Template;
 use axum::{
-	extract::{Query, State},
-	response::{Html, IntoResponse},
+	extract::{Path, Query, State},
+	response::{Html, IntoResponse, Redirect},
+	Json,
 };
 use axum_extra::{headers, TypedHeader};
-pub use context::SearchContext;
+use context::{Hits, Paging, Sort};
+pub use context::{SearchContext, Suggestion};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::Deserialize;
 
 use crate::{
 	auth,
 	auth::{namespace::Namespace, user::User},
-	ok, Context,
+	errors::WkError,
+	ok, page, Context,
 };
 
+/// Results per page, unless asked otherwise.
+const PER_PAGE: usize = 16;
+const MAX_PER_PAGE: usize = 100;
+/// Results past this can't be paged to, a page costs as much as all the
+/// results before it.
+const MAX_RESULTS: usize = 10_000;
+
 #[derive(Debug, Deserialize)]
 pub struct SearchParams {
 	#[serde(rename = "q")]
 	query: String,
+	/// "namespace/slug" to search only a page and its subpages.
+	scope: Option<String>,
+	#[serde(default)]
+	sort: Sort,
+	/// Starting at 1.
+	page: Option<usize>,
+	per_page: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuggestParams {
+	/// What's been typed so far.
+	#[serde(rename = "q")]
+	query: String,
 }
 
 #[derive(Template)]
 #[template(path = "search.html")]
 pub struct SearchResults {
 	query: String,
+	total: usize,
 	results: Vec<context::QueryResult>,
+	errors: Vec<String>,
+	/// Corrected query and its link.
+	did_you_mean: Option<(String, String)>,
+	/// Name and link of each way to sort, no link for the current one.
+	sorts: Vec<(&'static str, Option<String>)>,
+	previous: Option<String>,
+	next: Option<String>,
+}
+
+impl SearchParams {
+	/// Defaults to the first page of the most relevant results.
+	fn new(query: String) -> Self {
+		Self {
+			query,
+			scope: None,
+			sort: Sort::default(),
+			page: None,
+			per_page: None,
+		}
+	}
+
+	fn page(&self) -> usize {
+		self.page.unwrap_or(1).clamp(1, self.last_page())
+	}
+
+	/// Furthest page that stays within `MAX_RESULTS`.
+	fn last_page(&self) -> usize {
+		MAX_RESULTS / self.per_page()
+	}
+
+	fn per_page(&self) -> usize {
+		self.per_page.unwrap_or(PER_PAGE).clamp(1, MAX_PER_PAGE)
+	}
+
+	fn paging(&self) -> Paging {
+		Paging {
+			offset: (self.page() - 1).saturating_mul(self.per_page()),
+			limit: self.per_page(),
+		}
+	}
+
+	/// Link to the same search on another page, or sorted another way.
+	fn href(&self, page: usize, sort: Sort) -> String {
+		self.href_for(&self.query, page, sort)
+	}
+
+	/// Link to another query, scoped and shown the same way.
+	fn href_for(&self, query: &str, page: usize, sort: Sort) -> String {
+		let mut href = format!(
+			"/search?q={}",
+			utf8_percent_encode(query, NON_ALPHANUMERIC)
+		);
+		if let Some(ref scope) = self.scope {
+			href.push_str("&scope=");
+			href.extend(utf8_percent_encode(scope, NON_ALPHANUMERIC));
+		}
+		if sort != Sort::default() {
+			href.push_str(&format!("&sort={}", sort.as_str()));
+		}
+		if page > 1 {
+			href.push_str(&format!("&page={page}"));
+		}
+		if self.per_page() != PER_PAGE {
+			href.push_str(&format!("&per_page={}", self.per_page()));
+		}
+		href
+	}
+}
+
+impl SearchResults {
+	fn new(params: SearchParams, hits: Hits) -> Self {
+		let page = params.page();
+		let sorts = Sort::ALL
+			.into_iter()
+			.map(|sort| {
+				let href = (sort != params.sort).then(|| params.href(1, sort));
+				(sort.as_str(), href)
+			})
+			.collect();
+		let previous = (page > 1).then(|| params.href(page - 1, params.sort));
+		let next = (page < params.last_page()
+			&& page.saturating_mul(params.per_page()) < hits.total)
+			.then(|| params.href(page + 1, params.sort));
+		let did_you_mean = hits.did_you_mean.map(|query| {
+			let href = params.href_for(&query, 1, params.sort);
+			(query, href)
+		});
+
+		Self {
+			// TODO: Sanitize.
+			query: params.query,
+			total: hits.total,
+			results: hits.results,
+			errors: hits.errors,
+			did_you_mean,
+			sorts,
+			previous,
+			next,
+		}
+	}
 }
 
 #[axum_macros::debug_handler]
@@ -36,26 +164,180 @@ pub async fn get(
 	TypedHeader(cookies): TypedHeader<headers::Cookie>,
 	State(ctx): State<Arc<Context>>,
 ) -> impl IntoResponse {
-	let Context { search, db, .. } = ctx.as_ref();
+	let Context { db, .. } = ctx.as_ref();
 
 	let user = ok!(User::authenticated(db, cookies).await);
 
-	let namespaces = Namespace::list_with_access(db, user, auth::READ).await;
-	let ns_names = namespaces
-		.iter()
-		.map(|ns| ns.name.as_str())
-		.collect::<Vec<_>>();
+	let namespaces = Namespace::list_with_access(db, &user, auth::READ).await;
+	let (params, hits) = ok!(blocking(&ctx, move |search| {
+		let ns_names = namespaces
+			.iter()
+			.map(|ns| ns.name.as_str())
+			.collect::<Vec<_>>();
 
-	let results = search.read().unwrap().query(&params.query, ns_names);
+		let hits = search.query(
+			&params.query,
+			ns_names,
+			params.scope.as_deref(),
+			&user,
+			params.sort,
+			params.paging(),
+		);
+		(params, hits)
+	})
+	.await);
 
-	Html(
-		SearchResults {
-			// TODO: Sanitize.
-			query: params.query,
-			results,
+	Html(SearchResults::new(params, hits).render().unwrap()).into_response()
+}
+
+/// Pages with a tag, e.g. `/tags/runbook`.
+#[axum_macros::debug_handler]
+pub async fn tag(
+	Path(tag): Path<String>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	State(ctx): State<Arc<Context>>,
+) -> impl IntoResponse {
+	let Context { db, .. } = ctx.as_ref();
+
+	let user = ok!(User::authenticated(db, cookies).await);
+
+	let namespaces = Namespace::list_with_access(db, &user, auth::READ).await;
+	let params =
+		SearchParams::new(format!("tags:{}", page::normalize_tag(&tag)));
+	let (params, hits) = ok!(blocking(&ctx, move |search| {
+		let ns_names = namespaces
+			.iter()
+			.map(|ns| ns.name.as_str())
+			.collect::<Vec<_>>();
+
+		let hits = search.query(
+			&params.query,
+			ns_names,
+			None,
+			&user,
+			params.sort,
+			params.paging(),
+		);
+		(params, hits)
+	})
+	.await);
+
+	Html(SearchResults::new(params, hits).render().unwrap()).into_response()
+}
+
+/// Page titles for the search bar to offer as the user types, as JSON, e.g.
+/// `/search/suggest?q=run`.
+#[axum_macros::debug_handler]
+pub async fn suggest(
+	Query(params): Query<SuggestParams>,
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	State(ctx): State<Arc<Context>>,
+) -> impl IntoResponse {
+	let Context { db, .. } = ctx.as_ref();
+
+	let user = ok!(User::authenticated(db, cookies).await);
+
+	let namespaces = Namespace::list_with_access(db, &user, auth::READ).await;
+	let suggestions = ok!(blocking(&ctx, move |search| {
+		let ns_names = namespaces
+			.iter()
+			.map(|ns| ns.name.as_str())
+			.collect::<Vec<_>>();
+
+		search.suggest(&params.query, ns_names, &user)
+	})
+	.await);
+
+	Json(suggestions).into_response()
+}
+
+/// How the search indexer is keeping up, as plain text for a metrics scraper,
+/// e.g. `search_queue_depth 3`. A queue that stays full means edits are
+/// waiting on the index. Only for the meta user, like `/control`.
+#[axum_macros::debug_handler]
+pub async fn metrics(
+	TypedHeader(cookies): TypedHeader<headers::Cookie>,
+	State(ctx): State<Arc<Context>>,
+) -> impl IntoResponse {
+	let Context { search, db, .. } = ctx.as_ref();
+
+	if ok!(User::authenticated(db, cookies).await)
+		.filter(|user| user.name == User::META)
+		.is_none()
+	{
+		return Redirect::to("/?error=EPERM").into_response();
+	}
+
+	search
+		.metrics()
+		.into_iter()
+		.map(|(name, value)| format!("{name} {value}\n"))
+		.collect::<String>()
+		.into_response()
+}
+
+/// Runs `f` against the index off the async workers, as queries read the index
+/// and score documents synchronously.
+pub async fn blocking<T, F>(ctx: &Arc<Context>, f: F) -> Result<T, WkError>
+where
+	F: FnOnce(&SearchContext) -> T + Send + 'static,
+	T: Send + 'static,
+{
+	let ctx = ctx.clone();
+	tokio::task::spawn_blocking(move || f(&ctx.search))
+		.await
+		.map_err(|_| WkError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn search(page: usize, per_page: usize) -> SearchParams {
+		SearchParams {
+			page: Some(page),
+			per_page: Some(per_page),
+			..SearchParams::new("restore".to_string())
 		}
-		.render()
-		.unwrap(),
-	)
-	.into_response()
+	}
+
+	fn hits(total: usize) -> Hits {
+		Hits {
+			total,
+			results: vec![],
+			errors: vec![],
+			did_you_mean: None,
+		}
+	}
+
+	#[test]
+	fn paging_is_clamped() {
+		let params = search(0, 0);
+		assert_eq!(params.page(), 1);
+		assert_eq!(params.per_page(), 1);
+
+		let params = search(2, 1000);
+		assert_eq!(params.per_page(), MAX_PER_PAGE);
+		assert_eq!(params.paging().offset, MAX_PER_PAGE);
+	}
+
+	#[test]
+	fn overflowing_page_stays_within_the_results() {
+		let params = search(usize::MAX, usize::MAX);
+		assert_eq!(params.page(), params.last_page());
+		assert!(params.paging().offset + params.per_page() <= MAX_RESULTS);
+
+		let results = SearchResults::new(params, hits(usize::MAX));
+		assert!(results.next.is_none());
+		assert!(results.previous.is_some());
+	}
+
+	#[test]
+	fn next_only_while_there_are_more() {
+		assert!(SearchResults::new(search(1, 10), hits(11)).next.is_some());
+		assert!(SearchResults::new(search(1, 10), hits(10)).next.is_none());
+		assert!(SearchResults::new(search(1, 10), hits(0))
+			.previous
+			.is_none());
+	}
 }
@@ -0,0 +1,99 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, ImageReader, Limits};
+use rocksdb::TransactionDB;
+
+use crate::{
+	attachment::{get_blob, Attachment},
+	errors::WkError,
+	BLOB_CF,
+};
+
+/// Widths thumbnails are made at. Requested widths are rounded up to one of
+/// these, so each image has at most this many thumbnails.
+pub const WIDTHS: &[u32] = &[160, 320, 640, 1280];
+
+/// Images larger than this either way aren't thumbnailed, to bound the memory
+/// a decode can take.
+const MAX_DIMENSION: u32 = 12_000;
+
+/// A resized copy of an image attachment, as (content, content type).
+/// `None` if the attachment isn't an image we resize, or is already narrower
+/// than `width`, in which case the original should be served.
+pub async fn get(
+	db: &TransactionDB,
+	attachment: &Attachment,
+	width: u32,
+) -> Result<Option<(Vec<u8>, &'static str)>, WkError> {
+	let Some(format) = output_format(&attachment.content_type) else {
+		return Ok(None);
+	};
+	let width = snap(width);
+
+	// Cached next to the original, empty if the original is small enough.
+	let cf = db.cf_handle(BLOB_CF).unwrap();
+	let key = format!("{}/w{width}", attachment.hash);
+	let thumbnail = match db.get_cf(&cf, &key)? {
+		Some(cached) => cached,
+		None => {
+			let Some(original) = get_blob(db, &attachment.hash).await? else {
+				return Ok(None);
+			};
+			// Decoding is slow, keep it off the async workers.
+			let thumbnail = tokio::task::spawn_blocking(move || {
+				resize(&original, width, format)
+			})
+			.await
+			.map_err(|_| WkError::Io)?
+			.unwrap_or_default();
+			db.put_cf(&cf, &key, &thumbnail)?;
+			thumbnail
+		}
+	};
+
+	if thumbnail.is_empty() {
+		return Ok(None);
+	}
+	Ok(Some((thumbnail, format.to_mime_type())))
+}
+
+/// Rounds a requested width up to the nearest of `WIDTHS`.
+fn snap(width: u32) -> u32 {
+	WIDTHS
+		.iter()
+		.copied()
+		.find(|w| *w >= width)
+		.unwrap_or(WIDTHS[WIDTHS.len() - 1])
+}
+
+/// Photos stay JPEG, everything else becomes PNG.
+fn output_format(content_type: &str) -> Option<ImageFormat> {
+	match content_type {
+		"image/jpeg" => Some(ImageFormat::Jpeg),
+		"image/png" | "image/gif" | "image/webp" => Some(ImageFormat::Png),
+		_ => None,
+	}
+}
+
+/// `None` if the image can't be decoded or is no wider than `width`.
+fn resize(bytes: &[u8], width: u32, format: ImageFormat) -> Option<Vec<u8>> {
+	let mut reader = ImageReader::new(Cursor::new(bytes))
+		.with_guessed_format()
+		.ok()?;
+	let mut limits = Limits::default();
+	limits.max_image_width = Some(MAX_DIMENSION);
+	limits.max_image_height = Some(MAX_DIMENSION);
+	reader.limits(limits);
+
+	let image = reader.decode().ok()?;
+	if image.width() <= width {
+		return None;
+	}
+
+	let mut thumbnail = vec![];
+	image
+		.thumbnail(width, u32::MAX)
+		.write_to(&mut Cursor::new(&mut thumbnail), format)
+		.ok()?;
+	Some(thumbnail)
+}
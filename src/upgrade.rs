@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+
+use rocksdb::{Direction, IteratorMode, Transaction, TransactionDB};
+use tracing::{info, warn};
+
+use crate::{
+	auth::namespace::{Namespace, NamespaceV0},
+	encoding::{DbDecode, DbEncode},
+	errors::WkError,
+	include, page,
+	page::{Page, PageKey, PageV0},
+	HIST_CF, NSPC_CF, PAGE_CF, PEND_CF, SEQN_CF,
+};
+
+/// Format of the stored records, kept in `SEQN_CF`. Databases without it were
+/// made before records changed shape, in version 0. Up to version 1 page keys
+/// were bincode encoded.
+const VERSION: u64 = 2;
+const VERSION_KEY: &str = "version";
+
+/// Rewrites pages and namespaces stored in an older format in the current one,
+/// once, before anything reads them.
+pub async fn run(db: &TransactionDB) -> Result<(), WkError> {
+	let cf = db.cf_handle(SEQN_CF).unwrap();
+	let version = db.get_cf(&cf, VERSION_KEY)?.map_or(0, u64::dec);
+	if version == VERSION {
+		return Ok(());
+	}
+	if version < 2 {
+		let count = rekey(db, PAGE_CF)?;
+		rekey(db, PEND_CF)?;
+		info!("Rekeyed {count} pages");
+	}
+
+	let page_cf = db.cf_handle(PAGE_CF).unwrap();
+	let mut pages = vec![];
+	for entry in db.full_iterator_cf(&page_cf, IteratorMode::Start) {
+		let (key, value) = entry?;
+		if let Some(old) = old_page(&value)? {
+			pages.push((PageKey::from_bytes(key), old));
+		}
+	}
+	let count = pages.len();
+	// Slugs pages are moved to, so two can't end up with the same one.
+	let mut taken = HashSet::new();
+	for (key, old) in pages {
+		let ns = key.namespace().to_string();
+		let mut slug = new_slug(&old);
+		let moved = PageKey::new(&ns, &slug);
+		if moved.as_str() != key.as_str()
+			&& (taken.contains(moved.as_str())
+				|| db.get_cf(&page_cf, moved.as_str())?.is_some())
+		{
+			warn!(
+				"Can't move {} to {}, it's taken",
+				key.as_str(),
+				moved.as_str()
+			);
+			slug = old.slug().to_string();
+		}
+		taken.insert(PageKey::new(&ns, &slug).as_str().to_string());
+
+		// At once, so a page is never missing or stored twice.
+		let tx = db.transaction();
+		if slug != old.slug() {
+			rename(db, &tx, &ns, old.slug(), &slug)?;
+		}
+		let mut page = old.into_page(&slug);
+		// Also marks the page for the search index.
+		Page::put_in(db, &tx, &ns, &mut page)?;
+		tx.commit()?;
+	}
+	info!("Upgraded {count} pages");
+
+	let mut namespaces = vec![];
+	for entry in db
+		.full_iterator_cf(&db.cf_handle(NSPC_CF).unwrap(), IteratorMode::Start)
+	{
+		let (_, value) = entry?;
+		if Namespace::try_dec(&value).is_ok() {
+			continue;
+		}
+		namespaces.push(Namespace::from(NamespaceV0::try_dec(&value)?));
+	}
+	let count = namespaces.len();
+	for ns in namespaces {
+		Namespace::put(db, &ns).await?;
+	}
+	info!("Upgraded {count} namespaces");
+
+	db.put_cf(&cf, VERSION_KEY, VERSION.enc())?;
+	Ok(())
+}
+
+/// Stores the keys of a column family keyed by `PageKey` as they are, rather
+/// than bincode encoded, so a namespace's pages can be seeked.
+fn rekey(db: &TransactionDB, name: &str) -> Result<usize, WkError> {
+	let cf = db.cf_handle(name).unwrap();
+	let mut keys = vec![];
+	for entry in db.full_iterator_cf(&cf, IteratorMode::Start) {
+		let (key, value) = entry?;
+		if let Some(new) = old_key(&key) {
+			keys.push((key, new, value));
+		}
+	}
+	let count = keys.len();
+	for (old, new, value) in keys {
+		let tx = db.transaction();
+		tx.put_cf(&cf, new, value)?;
+		tx.delete_cf(&cf, old)?;
+		tx.commit()?;
+	}
+	Ok(count)
+}
+
+/// A key stored bincode encoded, `None` if it's already stored as is, e.g. by
+/// an upgrade that stopped half way.
+fn old_key(key: &[u8]) -> Option<String> {
+	String::try_dec(key).ok()
+}
+
+/// A page stored in the old format, `None` if it's in the current one, e.g.
+/// written by this version before it kept track of it.
+fn old_page(value: &[u8]) -> Result<Option<PageV0>, WkError> {
+	if Page::try_dec(value).is_ok() {
+		return Ok(None);
+	}
+	PageV0::try_dec(value).map(Some)
+}
+
+/// Old slugs were lowercase and hyphenated, pages get the slug their title
+/// would get now, e.g. "search-syntax" => "Search_syntax".
+fn new_slug(old: &PageV0) -> String {
+	match page::slugify(old.title()) {
+		slug if slug.is_empty() => old.slug().to_string(),
+		slug => slug,
+	}
+}
+
+/// Moves a page's history and includes from one slug to another, and drops
+/// its record under the old one.
+fn rename(
+	db: &TransactionDB,
+	tx: &Transaction<'_, TransactionDB>,
+	ns: &str,
+	from: &str,
+	to: &str,
+) -> Result<(), WkError> {
+	tx.delete_cf(
+		&db.cf_handle(PAGE_CF).unwrap(),
+		PageKey::new(ns, from).as_str(),
+	)?;
+
+	// "ns/slug/1", "ns/slug/VERSION"...
+	let history = db.cf_handle(HIST_CF).unwrap();
+	let prefix = format!("{ns}/{from}/");
+	for entry in db.iterator_cf(
+		&history,
+		IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+	) {
+		let (key, value) = entry?;
+		let Some(rest) = key.strip_prefix(prefix.as_bytes()) else {
+			break;
+		};
+		let mut moved = format!("{ns}/{to}/").into_bytes();
+		moved.extend_from_slice(rest);
+		tx.put_cf(&history, moved, value)?;
+		tx.delete_cf(&history, key)?;
+	}
+
+	include::rename(db, tx, &format!("{ns}/{from}"), &format!("{ns}/{to}"))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BINCODE_CONFIG;
+
+	/// A page as it was stored before the upgrade, field by field.
+	fn stored_v0(title: &str, slug: &str) -> Vec<u8> {
+		let page = (
+			title.to_string(),
+			slug.to_string(),
+			0o644_u16,
+			"Body".to_string(),
+			Some("meta".to_string()),
+		);
+		bincode::encode_to_vec(page, BINCODE_CONFIG).unwrap()
+	}
+
+	#[test]
+	fn old_pages_are_told_apart() {
+		let old = old_page(&stored_v0("Search syntax", "search-syntax"))
+			.unwrap()
+			.unwrap();
+		assert_eq!(old.slug(), "search-syntax");
+
+		let mut current = Page::new("Search syntax", 0o644, None, None);
+		current.set_content("Body").unwrap();
+		assert!(old_page(&current.enc()).unwrap().is_none());
+	}
+
+	#[test]
+	fn old_lowercase_slugs_are_slugged_again() {
+		let old = old_page(&stored_v0("Search syntax", "search-syntax"))
+			.unwrap()
+			.unwrap();
+		let slug = new_slug(&old);
+		assert_eq!(slug, "Search_syntax");
+		let page = old.into_page(&slug);
+		assert_eq!(page.slug(), "Search_syntax");
+		assert_eq!(page.content(), "Body");
+		assert_eq!(page.owner(), Some("meta"));
+
+		// Seeded pages are titled by their file name.
+		let old = old_page(&stored_v0("Permissions", "permissions"))
+			.unwrap()
+			.unwrap();
+		assert_eq!(new_slug(&old), "Permissions");
+	}
+
+	#[test]
+	fn old_keys_are_told_apart() {
+		let key = PageKey::new("meta", "Permissions");
+		let encoded = key.as_str().to_string().enc();
+		assert_eq!(old_key(&encoded).as_deref(), Some("meta/Permissions"));
+		assert_eq!(old_key(key.as_str().as_bytes()), None);
+	}
+
+	#[test]
+	fn titles_without_a_slug_keep_theirs() {
+		let old = old_page(&stored_v0("???", "x")).unwrap().unwrap();
+		assert_eq!(new_slug(&old), "x");
+	}
+}
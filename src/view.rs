@@ -3,18 +3,25 @@ use std::sync::Arc;
 use askama::Template;
 use axum::{
 	extract::{Path, State},
-	response::{Html, IntoResponse},
+	response::{Html, IntoResponse, Redirect},
 };
 use axum_extra::{headers, TypedHeader};
+use rocksdb::TransactionDB;
 
 use crate::{
+	attachment::FileLink,
 	auth,
 	auth::{
 		namespace::Namespace,
 		user::{User, UserView},
 	},
-	exists, not_found, ok,
-	page::Page,
+	exists,
+	front_matter::PageMeta,
+	include, not_found, ok, page,
+	page::{Page, PageKey},
+	render::TocEntry,
+	search,
+	search::Suggestion,
 	Context,
 };
 
@@ -22,11 +29,36 @@ use crate::{
 #[template(path = "view.html")]
 pub struct ViewTemplate<'a> {
 	pub(crate) title: &'a str,
-	pub(crate) body: &'a str,
+	pub(crate) html: String,
+	pub(crate) toc: Vec<TocEntry>,
+	pub(crate) namespace: &'a str,
 	pub(crate) slug: &'a str,
+	pub(crate) url: String,
+	pub(crate) breadcrumbs: Vec<PageLink>,
+	pub(crate) children: Vec<PageLink>,
+	pub(crate) used_by: Vec<PageLink>,
+	pub(crate) related: Vec<Suggestion>,
+	pub(crate) files: Vec<FileLink>,
+	pub(crate) tags: Vec<&'a str>,
+	pub(crate) meta: &'a PageMeta,
 	pub user: Option<UserView>,
 }
 
+pub struct PageLink {
+	title: String,
+	url: String,
+}
+
+impl PageLink {
+	fn new(ns: &str, slug: &str) -> Self {
+		let segment = slug.rsplit('/').next().unwrap_or(slug);
+		Self {
+			title: segment.replace('_', " "),
+			url: page::url(ns, slug),
+		}
+	}
+}
+
 pub async fn get(
 	Path((ns, slug)): Path<(String, String)>,
 	TypedHeader(cookies): TypedHeader<headers::Cookie>,
@@ -41,24 +73,96 @@ pub async fn get(
 		return not_found().await.into_response();
 	}
 
-	// TODO: Sanitize.
 	if let Some(page) = Page::get(db, &ns.name, &slug).await {
 		if !page.user_has_access(&user, &ns.name, auth::READ) {
 			return not_found().await.into_response();
 		}
 
+		let breadcrumbs = page
+			.slug()
+			.match_indices('/')
+			.map(|(i, _)| PageLink::new(&ns.name, &page.slug()[..i]))
+			.collect();
+		let children = Page::children(db, &ns.name, page.slug())
+			.await
+			.filter(|child| child.user_has_access(&user, &ns.name, auth::READ))
+			.map(|child| PageLink::new(&ns.name, child.slug()))
+			.collect();
+
+		let mut used_by = vec![];
+		for (includer_ns, includer_slug) in
+			ok!(include::used_by(db, &ns.name, page.slug()).await)
+		{
+			if can_read(db, &user, &includer_ns, &includer_slug).await {
+				used_by.push(PageLink::new(&includer_ns, &includer_slug));
+			}
+		}
+
+		let namespaces =
+			Namespace::list_with_access(db, &user, auth::READ).await;
+		let key = PageKey::new(&ns.name, page.slug());
+		let (page, user, related) =
+			ok!(search::blocking(&ctx, move |search| {
+				let related = search.related(
+					&key,
+					&page,
+					namespaces.iter().map(|ns| ns.name.as_str()).collect(),
+					&user,
+				);
+				(page, user, related)
+			})
+			.await);
+
+		let rendered = include::render(db, &user, &ns, &page).await;
+
 		Html(
 			ViewTemplate {
 				title: page.title(),
-				body: page.content(),
+				html: rendered.html,
+				toc: rendered.toc,
+				namespace: &ns.name,
 				slug: page.slug(),
+				url: page::url(&ns.name, page.slug()),
+				breadcrumbs,
+				children,
+				used_by,
+				related,
+				files: page
+					.attachments()
+					.iter()
+					.map(|file| FileLink::new(&ns.name, page.slug(), file))
+					.collect(),
+				tags: page.tags(),
+				meta: page.meta(),
 				user: user.map(UserView::new),
 			}
 			.render()
 			.unwrap(),
 		)
 		.into_response()
+	} else if let Some(page) = Page::find(db, &ns.name, &slug)
+		.await
+		.filter(|page| page.user_has_access(&user, &ns.name, auth::READ))
+	{
+		// Case and underscore variants of a slug go to the canonical URL.
+		Redirect::permanent(&page::url(&ns.name, page.slug())).into_response()
 	} else {
 		not_found().await.into_response()
 	}
 }
+
+async fn can_read(
+	db: &TransactionDB,
+	user: &Option<User>,
+	ns: &str,
+	slug: &str,
+) -> bool {
+	match Namespace::get(db, ns).await {
+		Ok(Some(ns)) if ns.user_has_access(user, auth::READ) => {
+			Page::get(db, &ns.name, slug).await.is_some_and(|page| {
+				page.user_has_access(user, &ns.name, auth::READ)
+			})
+		}
+		_ => false,
+	}
+}